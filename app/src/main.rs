@@ -5,20 +5,22 @@ use httpRs::response::respond;
 use httpRs::router::HandlerResponse;
 use httpRs::router::Html;
 use httpRs::router::Router;
+use httpRs::server::shutdown_signal;
+use httpRs::state::State;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
 use std::io;
 
 fn test_handler(
-    _req: NewRequestType,
-    state: AppState,
+    mut req: NewRequestType,
+    state: State<AppState>,
     _extract: HashMap<String, String>,
 ) -> HandlerResponse<'static> {
     Box::pin(async move {
-        let json_body: JsonTest = _req.from_json_to_struct().unwrap();
+        let json_body: JsonTest = req.read_json_to_struct().await.unwrap();
         println!("{:?}", json_body);
-        respond(Html(state.hello_page))
+        respond(Html(state.hello_page.clone()))
     })
 }
 
@@ -32,7 +34,7 @@ pub struct JsonTest {
     pub page: String,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Debug)]
 pub struct AppState {
     pub hello_page: String,
 }
@@ -49,7 +51,7 @@ async fn main() -> io::Result<()> {
         .with_state(AppState { hello_page: file })
         .fallback(httpRs::router::Handler::Without(fallback))
         .make_into_serveable();
-    router.serve("localhost:4000").await;
-
-    Ok(())
+    router
+        .serve_with_shutdown("localhost:4000", shutdown_signal())
+        .await
 }
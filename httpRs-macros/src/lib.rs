@@ -0,0 +1,489 @@
+#![forbid(unsafe_code)]
+// named after httpRs
+#![allow(non_snake_case)]
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::format_ident;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::punctuated::Punctuated;
+use syn::Attribute;
+use syn::Data;
+use syn::DeriveInput;
+use syn::Error;
+use syn::Expr;
+use syn::ExprLit;
+use syn::Fields;
+use syn::FnArg;
+use syn::GenericArgument;
+use syn::ItemFn;
+use syn::Lit;
+use syn::LitInt;
+use syn::LitStr;
+use syn::Meta;
+use syn::Pat;
+use syn::PathArguments;
+use syn::Token;
+use syn::Type;
+
+// Route attributes for async handler functions, e.g.
+//
+// #[get("/user/:id")]
+// async fn show_user(state: State<AppState>, id: u32) -> impl IntoResp {...}
+//
+// The function is turned into a registration function that adds the handler to a Router, see
+// routes! and Router::mount. Arguments can be the request (NewRequestType), the state (State<T>)
+// and the params of the path, which are parsed with FromStr and taken by name. A path param
+// without an argument or an argument that is not a path param does not compile
+#[proc_macro_attribute]
+pub fn get(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("GET", attr, item)
+}
+#[proc_macro_attribute]
+pub fn post(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("POST", attr, item)
+}
+#[proc_macro_attribute]
+pub fn put(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("PUT", attr, item)
+}
+#[proc_macro_attribute]
+pub fn patch(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("PATCH", attr, item)
+}
+#[proc_macro_attribute]
+pub fn delete(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("DELETE", attr, item)
+}
+#[proc_macro_attribute]
+pub fn head(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("HEAD", attr, item)
+}
+#[proc_macro_attribute]
+pub fn options(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route("OPTIONS", attr, item)
+}
+
+// Collects handlers declared with the route attributes for Router::mount,
+// e.g. router.mount(routes![show_user, list_users])
+#[proc_macro]
+pub fn routes(input: TokenStream) -> TokenStream {
+    let parser = Punctuated::<syn::Path, Token![,]>::parse_terminated;
+    let handlers = parse_macro_input!(input with parser);
+    let handlers = handlers.iter();
+    quote!(::std::vec![#(#handlers as ::httpRs::router::RouteFn<_>),*]).into()
+}
+
+fn route(method: &str, attr: TokenStream, item: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(attr as LitStr);
+    let func = parse_macro_input!(item as ItemFn);
+    match expand(method, path, func) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// How a handler argument is filled in
+enum Arg {
+    Request,
+    State,
+    Param(String),
+}
+
+fn expand(method: &str, path: LitStr, func: ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &func.sig;
+    let name = &sig.ident;
+    if sig.asyncness.is_none() {
+        return Err(Error::new_spanned(
+            sig.fn_token,
+            "route handlers have to be async",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &sig.generics,
+            "route handlers can not be generic",
+        ));
+    }
+    let route = path.value();
+    if !route.starts_with('/') {
+        return Err(Error::new_spanned(&path, "the path has to start with /"));
+    }
+    let params: Vec<&str> = route
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix(':'))
+        .collect();
+    // the router keeps one value per name, the second one would be lost
+    let repeated = params
+        .iter()
+        .enumerate()
+        .find(|(idx, param)| params[..*idx].contains(param));
+    if let Some((_, param)) = repeated {
+        return Err(Error::new_spanned(
+            &path,
+            format!("the path param `{param}` is used more than once"),
+        ));
+    }
+
+    let mut state_type = None;
+    let mut args = Vec::new();
+    for input in sig.inputs.iter() {
+        let input = match input {
+            FnArg::Typed(input) => input,
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new_spanned(
+                    receiver,
+                    "route handlers can not take self",
+                ))
+            }
+        };
+        let arg = match type_name(&input.ty).as_deref() {
+            Some("NewRequestType") => Arg::Request,
+            Some("State") => {
+                if state_type.is_some() {
+                    return Err(Error::new_spanned(
+                        input,
+                        "the state can only be taken once",
+                    ));
+                }
+                state_type = Some(state_inner_type(&input.ty)?);
+                Arg::State
+            }
+            _ => {
+                let param = match &*input.pat {
+                    Pat::Ident(pat) => pat.ident.to_string(),
+                    pat => {
+                        return Err(Error::new_spanned(
+                            pat,
+                            "path params have to be taken by name",
+                        ))
+                    }
+                };
+                if !params.contains(&param.as_str()) {
+                    return Err(Error::new_spanned(
+                        &input.pat,
+                        format!("`{param}` is not a param of the path {route}"),
+                    ));
+                }
+                Arg::Param(param)
+            }
+        };
+        if matches!(arg, Arg::Request) && args.iter().any(|(arg, _)| matches!(arg, Arg::Request)) {
+            return Err(Error::new_spanned(
+                input,
+                "the request can only be taken once",
+            ));
+        }
+        args.push((arg, &input.ty));
+    }
+    for param in params.iter() {
+        let taken = args
+            .iter()
+            .any(|(arg, _)| matches!(arg, Arg::Param(name) if name == param));
+        if !taken {
+            return Err(Error::new_spanned(
+                &path,
+                format!("the path param `{param}` is not an argument of {name}"),
+            ));
+        }
+    }
+
+    // the router checks the method, see Router::add_method_handler
+    let bindings: Vec<_> = (0..args.len())
+        .map(|i| format_ident!("__httprs_arg{}", i))
+        .collect();
+    let values = args.iter().map(|(arg, ty)| match arg {
+        Arg::Request => quote!(req),
+        Arg::State => quote! {
+            match state {
+                ::std::option::Option::Some(state) => state,
+                ::std::option::Option::None => {
+                    return ::httpRs::response::respond((
+                        ::httpRs::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        ::std::string::String::from("Missing state"),
+                    ))
+                }
+            }
+        },
+        Arg::Param(param) => quote! {
+            match extracts.get(#param).map(|value| value.parse::<#ty>()) {
+                ::std::option::Option::Some(::std::result::Result::Ok(value)) => value,
+                _ => return ::httpRs::response::respond(::httpRs::http::StatusCode::BAD_REQUEST),
+            }
+        },
+    });
+
+    let method_variant = format_ident!("{}", method);
+    let vis = &func.vis;
+    let (generics, state) = match state_type {
+        Some(state) => (quote!(), quote!(#state)),
+        None => (
+            quote!(<__HttpRsState: Send + Sync + 'static>),
+            quote!(__HttpRsState),
+        ),
+    };
+    Ok(quote! {
+        #vis fn #name #generics(
+            router: ::httpRs::router::Router<#state>,
+        ) -> ::std::result::Result<::httpRs::router::Router<#state>, ::httpRs::router::RouteError> {
+            #func
+            let handler = ::httpRs::router::Handler::Erased(::std::sync::Arc::new(
+                |req: ::httpRs::parse::NewRequestType,
+                 state: ::std::option::Option<::httpRs::state::State<#state>>,
+                 extracts: ::std::option::Option<::std::collections::HashMap<
+                    ::std::string::String,
+                    ::std::string::String,
+                >>|
+                 -> ::httpRs::router::HandlerResponse<'static> {
+                    ::std::boxed::Box::pin(async move {
+                        let extracts = extracts.unwrap_or_default();
+                        #(let #bindings = #values;)*
+                        ::httpRs::response::respond(#name(#(#bindings),*).await)
+                    })
+                },
+            ));
+            let mut router = router;
+            router.add_method_handler(::httpRs::types::Method::#method_variant, #path, handler)
+        }
+    })
+}
+
+// Last segment of a type path, e.g. State for httpRs::state::State<AppState>
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string()),
+        _ => None,
+    }
+}
+fn state_inner_type(ty: &Type) -> syn::Result<&Type> {
+    if let Type::Path(path) = ty {
+        if let Some(segment) = path.path.segments.last() {
+            if let PathArguments::AngleBracketed(args) = &segment.arguments {
+                if let Some(GenericArgument::Type(inner)) = args.args.first() {
+                    return Ok(inner);
+                }
+            }
+        }
+    }
+    Err(Error::new_spanned(ty, "expected State<YourState>"))
+}
+
+// Implements IntoResp for error enums and response structs.
+//
+// #[status(404)] sets the status, #[body = "json"] or #[body = "text"] the body and
+// #[content_type = "..."] the Content-type, which is application/json or text/plain by default.
+// A text body is the Display of the value, a json body the fields of the variant (one unnamed field
+// on its own, several as an array, named ones as an object) or the whole struct.
+// Attributes on an enum apply to all variants that do not set their own. Enums default to a 500
+// without a body, structs to a 200 and need a body or a content type, which picks the body as
+// json if it contains json and as text otherwise
+#[proc_macro_derive(IntoResp, attributes(status, body, content_type))]
+pub fn derive_into_resp(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_into_resp(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BodyKind {
+    Json,
+    Text,
+}
+#[derive(Clone, Default)]
+struct RespAttrs {
+    status: Option<u16>,
+    body: Option<BodyKind>,
+    content_type: Option<String>,
+}
+impl RespAttrs {
+    // Reads the attributes, the ones that are not set are taken from inherited
+    fn parse(attrs: &[Attribute], inherited: &RespAttrs) -> syn::Result<RespAttrs> {
+        let mut parsed = inherited.clone();
+        for attr in attrs {
+            if attr.path().is_ident("status") {
+                let status: LitInt = attr.parse_args()?;
+                let code = status.base10_parse::<u16>()?;
+                if !(100..=999).contains(&code) {
+                    return Err(Error::new_spanned(status, "not a valid status code"));
+                }
+                parsed.status = Some(code);
+            } else if attr.path().is_ident("body") {
+                let kind = string_value(attr)?;
+                parsed.body = Some(match kind.value().as_str() {
+                    "json" => BodyKind::Json,
+                    "text" => BodyKind::Text,
+                    _ => return Err(Error::new_spanned(kind, "expected \"json\" or \"text\"")),
+                });
+            } else if attr.path().is_ident("content_type") {
+                parsed.content_type = Some(string_value(attr)?.value());
+            }
+        }
+        Ok(parsed)
+    }
+    // The body and its content type, None if there is no body
+    fn body(&self) -> Option<(BodyKind, String)> {
+        let kind = match (self.body, &self.content_type) {
+            (Some(kind), _) => kind,
+            (None, Some(content_type)) if content_type.contains("json") => BodyKind::Json,
+            (None, Some(_)) => BodyKind::Text,
+            (None, None) => return None,
+        };
+        let content_type = match (&self.content_type, kind) {
+            (Some(content_type), _) => content_type.clone(),
+            (None, BodyKind::Json) => "application/json".to_string(),
+            (None, BodyKind::Text) => "text/plain; charset=utf-8".to_string(),
+        };
+        Some((kind, content_type))
+    }
+}
+fn string_value(attr: &Attribute) -> syn::Result<LitStr> {
+    if let Meta::NameValue(meta) = &attr.meta {
+        if let Expr::Lit(ExprLit {
+            lit: Lit::Str(value),
+            ..
+        }) = &meta.value
+        {
+            return Ok(value.clone());
+        }
+    }
+    Err(Error::new_spanned(
+        attr,
+        "expected a string, e.g. #[body = \"json\"]",
+    ))
+}
+
+fn expand_into_resp(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let type_attrs = RespAttrs::parse(&input.attrs, &RespAttrs::default())?;
+    let response = match &input.data {
+        Data::Struct(_) => {
+            let (kind, content_type) = match type_attrs.body() {
+                Some(body) => body,
+                None => {
+                    return Err(Error::new_spanned(
+                        name,
+                        "mark the struct with #[content_type = \"...\"] or #[body = \"json\"]",
+                    ))
+                }
+            };
+            let body = match kind {
+                BodyKind::Json => quote!(::httpRs::serde_json::to_string(self)),
+                BodyKind::Text => quote!(::std::result::Result::<_, ()>::Ok(
+                    ::std::string::ToString::to_string(self)
+                )),
+            };
+            response_tokens(type_attrs.status.unwrap_or(200), &content_type, body)
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for variant in data.variants.iter() {
+                let attrs = RespAttrs::parse(&variant.attrs, &type_attrs)?;
+                let status = attrs.status.unwrap_or(500);
+                let ident = &variant.ident;
+                let (kind, content_type) = match attrs.body() {
+                    Some(body) => body,
+                    None => {
+                        let pattern = ignored_fields(&variant.fields);
+                        arms.push(quote! {
+                            Self::#ident #pattern => ::httpRs::response::IntoResp::into_response(
+                                &::httpRs::http::StatusCode::from_u16(#status)
+                                    .unwrap_or(::httpRs::http::StatusCode::INTERNAL_SERVER_ERROR),
+                            ),
+                        });
+                        continue;
+                    }
+                };
+                let (pattern, body) = match kind {
+                    BodyKind::Text => (
+                        ignored_fields(&variant.fields),
+                        quote!(::std::result::Result::<_, ()>::Ok(
+                            ::std::string::ToString::to_string(self)
+                        )),
+                    ),
+                    BodyKind::Json => json_fields(variant)?,
+                };
+                let response = response_tokens(status, &content_type, body);
+                arms.push(quote!(Self::#ident #pattern => { #response }));
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                name,
+                "IntoResp can not be derived for unions",
+            ))
+        }
+    };
+    Ok(quote! {
+        impl #impl_generics ::httpRs::response::IntoResp for #name #ty_generics #where_clause {
+            fn into_response(&self) -> ::std::vec::Vec<u8> {
+                #response
+            }
+        }
+    })
+}
+// body has to evaluate to a Result<String, _>, an error is answered with a 500
+fn response_tokens(status: u16, content_type: &str, body: TokenStream2) -> TokenStream2 {
+    quote! {
+        match #body {
+            ::std::result::Result::Ok(body) => ::httpRs::response::response_with_body(
+                ::httpRs::http::StatusCode::from_u16(#status)
+                    .unwrap_or(::httpRs::http::StatusCode::INTERNAL_SERVER_ERROR),
+                #content_type,
+                &body,
+            ),
+            ::std::result::Result::Err(_) => ::httpRs::response::IntoResp::into_response(
+                &::httpRs::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        }
+    }
+}
+fn ignored_fields(fields: &Fields) -> TokenStream2 {
+    match fields {
+        Fields::Named(_) => quote!({ .. }),
+        Fields::Unnamed(_) => quote!((..)),
+        Fields::Unit => quote!(),
+    }
+}
+// The pattern binding the fields of a variant and its json body
+fn json_fields(variant: &syn::Variant) -> syn::Result<(TokenStream2, TokenStream2)> {
+    match &variant.fields {
+        Fields::Named(fields) => {
+            let idents: Vec<_> = fields.named.iter().map(|field| &field.ident).collect();
+            let keys = idents
+                .iter()
+                .map(|ident| ident.as_ref().map(|ident| ident.to_string()));
+            Ok((
+                quote!({ #(#idents),* }),
+                quote!(::httpRs::serde_json::to_string(
+                    &::httpRs::serde_json::json!({ #(#keys: #idents),* })
+                )),
+            ))
+        }
+        Fields::Unnamed(fields) => {
+            let bindings: Vec<_> = (0..fields.unnamed.len())
+                .map(|i| format_ident!("field{}", i))
+                .collect();
+            let body = match bindings.as_slice() {
+                [field] => quote!(::httpRs::serde_json::to_string(#field)),
+                _ => quote!(::httpRs::serde_json::to_string(&(#(#bindings),*))),
+            };
+            Ok((quote!((#(#bindings),*)), body))
+        }
+        Fields::Unit => Err(Error::new_spanned(
+            variant,
+            "a json body needs fields, use #[body = \"text\"] or no body",
+        )),
+    }
+}
@@ -0,0 +1,216 @@
+#![forbid(unsafe_code)]
+use http::StatusCode;
+use std::time::Duration;
+
+// Settings for every connection of a router, set with Router::with_config.
+// None disables a timeout
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // Time a client has to send the request line and all headers, 408 when it is exceeded.
+    // For the first request on a connection this starts when the connection is accepted so
+    // clients that connect and never send anything are dropped as well
+    pub header_timeout: Option<Duration>,
+    // How long a kept alive connection may wait for the next request before it is closed
+    pub keep_alive_timeout: Option<Duration>,
+    pub parse_mode: ParseMode,
+    // Merge repeated slashes in request paths, so //user//1 is routed like /user/1
+    pub merge_slashes: bool,
+    pub trailing_slash: TrailingSlash,
+    // Defaults for all routes, a route registered with Router::add_handler_with_config
+    // overrides them
+    pub route: RouteConfig,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            header_timeout: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            parse_mode: ParseMode::Strict,
+            merge_slashes: false,
+            trailing_slash: TrailingSlash::Strict,
+            route: RouteConfig {
+                body_timeout: Some(Duration::from_secs(30)),
+                min_body_rate: None,
+                handler_timeout: None,
+                handler_timeout_status: None,
+                limits: Limits {
+                    request_line: Some(8 * 1024),
+                    header_count: Some(100),
+                    header_bytes: Some(64 * 1024),
+                    body: Some(2 * 1024 * 1024),
+                },
+            },
+        }
+    }
+}
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = Some(timeout);
+        self
+    }
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = Some(timeout);
+        self
+    }
+    pub fn parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+    pub fn merge_slashes(mut self, merge: bool) -> Self {
+        self.merge_slashes = merge;
+        self
+    }
+    pub fn trailing_slash(mut self, policy: TrailingSlash) -> Self {
+        self.trailing_slash = policy;
+        self
+    }
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.route.body_timeout = Some(timeout);
+        self
+    }
+    pub fn min_body_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.route.min_body_rate = Some(bytes_per_sec);
+        self
+    }
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.route.handler_timeout = Some(timeout);
+        self
+    }
+    pub fn handler_timeout_status(mut self, status: StatusCode) -> Self {
+        self.route.handler_timeout_status = Some(status);
+        self
+    }
+    // Replaces all default limits, the ones that are not set are unlimited
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.route.limits = limits;
+        self
+    }
+}
+
+// How requests are parsed, see parse::parse_request_strict
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    // Follows RFC 9112 and rejects everything that could be read differently by a proxy in
+    // front of the server, which is what request smuggling relies on
+    #[default]
+    Strict,
+    // The old parser, accepts bare LF, any version string and sloppy headers.
+    // Only meant for legacy clients that can not be fixed
+    Lenient,
+}
+
+// Whether /users/ matches a route registered as /users and the other way around
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    // Only the exact path matches, both can be registered as different routes
+    #[default]
+    Strict,
+    // Both match if only one of them is registered
+    Permissive,
+    // Sends a 308 to the registered one, the query string is kept
+    Redirect,
+}
+
+// Per route settings, every field that is None falls back to the one of ServerConfig::route
+#[derive(Clone, Debug, Default)]
+pub struct RouteConfig {
+    // Time to receive the whole body once the headers are read, 408 when it is exceeded
+    pub body_timeout: Option<Duration>,
+    // Clients sending the body slower than this are dropped with a 408 after a short grace
+    // period, so a body can not be trickled in a byte at a time until body_timeout
+    pub min_body_rate: Option<u64>,
+    pub handler_timeout: Option<Duration>,
+    // Sent when the handler takes longer than handler_timeout, 503 if not set.
+    // 504 fits better for handlers that mostly wait on an upstream service
+    pub handler_timeout_status: Option<StatusCode>,
+    pub limits: Limits,
+}
+impl RouteConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.body_timeout = Some(timeout);
+        self
+    }
+    pub fn min_body_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.min_body_rate = Some(bytes_per_sec);
+        self
+    }
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+    pub fn handler_timeout_status(mut self, status: StatusCode) -> Self {
+        self.handler_timeout_status = Some(status);
+        self
+    }
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+    // Fills the fields that are not set from defaults
+    pub fn or(&self, defaults: &RouteConfig) -> RouteConfig {
+        RouteConfig {
+            body_timeout: self.body_timeout.or(defaults.body_timeout),
+            min_body_rate: self.min_body_rate.or(defaults.min_body_rate),
+            handler_timeout: self.handler_timeout.or(defaults.handler_timeout),
+            handler_timeout_status: self
+                .handler_timeout_status
+                .or(defaults.handler_timeout_status),
+            limits: self.limits.or(&defaults.limits),
+        }
+    }
+}
+
+// Size limits for a request in bytes, None means unlimited.
+// The limits of ServerConfig are enforced while the request is read. The route is only known
+// once the request line and the headers were read, so a route can lower the limits for them
+// but not raise them above the ones of the server. The body limit of a route is checked
+// against Content-Length before any of the body is read
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    // 414 when exceeded
+    pub request_line: Option<usize>,
+    // 431 when exceeded
+    pub header_count: Option<usize>,
+    // All header lines together, 431 when exceeded
+    pub header_bytes: Option<usize>,
+    // 413 when exceeded
+    pub body: Option<usize>,
+}
+impl Limits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn request_line(mut self, bytes: usize) -> Self {
+        self.request_line = Some(bytes);
+        self
+    }
+    pub fn header_count(mut self, count: usize) -> Self {
+        self.header_count = Some(count);
+        self
+    }
+    pub fn header_bytes(mut self, bytes: usize) -> Self {
+        self.header_bytes = Some(bytes);
+        self
+    }
+    pub fn body(mut self, bytes: usize) -> Self {
+        self.body = Some(bytes);
+        self
+    }
+    pub fn or(&self, defaults: &Limits) -> Limits {
+        Limits {
+            request_line: self.request_line.or(defaults.request_line),
+            header_count: self.header_count.or(defaults.header_count),
+            header_bytes: self.header_bytes.or(defaults.header_bytes),
+            body: self.body.or(defaults.body),
+        }
+    }
+}
+pub fn exceeds(len: usize, limit: Option<usize>) -> bool {
+    matches!(limit, Some(limit) if len > limit)
+}
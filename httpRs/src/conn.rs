@@ -0,0 +1,245 @@
+#![forbid(unsafe_code)]
+use crate::config::exceeds;
+use crate::config::Limits;
+use crate::config::ParseMode;
+use crate::parse::find_bare_line_break;
+use crate::server::Connection;
+use bytes::BytesMut;
+use http::StatusCode;
+use http::Version;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+// A client sending the body slower than RouteConfig::min_body_rate is only dropped after this
+const BODY_RATE_GRACE: Duration = Duration::from_secs(1);
+
+// Outcome of reading from a connection with a deadline
+#[derive(Debug, PartialEq)]
+pub enum Read<T> {
+    Done(T),
+    // The client closed the connection
+    Closed,
+    TimedOut,
+    // The request broke one of the Limits and should be answered with this status
+    Rejected(StatusCode),
+}
+
+pub fn deadline(start: Instant, timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| start + timeout)
+}
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+// Runs fut until the deadline, None if it did not finish in time
+pub async fn until<F: Future>(deadline: Option<Instant>, fut: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+// Reads whatever is available into buf
+pub async fn read_more<S: Connection>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    deadline: Option<Instant>,
+) -> std::io::Result<Read<()>> {
+    match until(deadline, socket.read_buf(buf)).await {
+        None => Ok(Read::TimedOut),
+        Some(Ok(0)) => Ok(Read::Closed),
+        Some(Ok(_)) => Ok(Read::Done(())),
+        Some(Err(e)) => Err(e),
+    }
+}
+
+// Reads until buf holds the request line and all headers, returns their length including
+// the empty line. Anything after that is left in buf.
+// Stops reading as soon as the head grows past limits. In strict mode a bare LF is rejected
+// right away, the head would never end otherwise since the end is searched as CRLF CRLF
+pub async fn read_head<S: Connection>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    deadline: Option<Instant>,
+    limits: &Limits,
+    mode: ParseMode,
+) -> std::io::Result<Read<usize>> {
+    let mut searched = 0;
+    loop {
+        if let Some(end) = find_head_end(&buf[searched..], mode) {
+            let len = searched + end;
+            return match check_head(&buf[..len], limits) {
+                Ok(()) => Ok(Read::Done(len)),
+                Err(status) => Ok(Read::Rejected(status)),
+            };
+        }
+        if let Err(status) = check_head(buf, limits) {
+            return Ok(Read::Rejected(status));
+        }
+        if mode == ParseMode::Strict && find_bare_line_break(buf).is_some() {
+            return Ok(Read::Rejected(StatusCode::BAD_REQUEST));
+        }
+        // the end could be split over two reads
+        searched = buf.len().saturating_sub(3);
+        match read_more(socket, buf, deadline).await? {
+            Read::Done(()) => (),
+            Read::Closed => return Ok(Read::Closed),
+            Read::TimedOut => return Ok(Read::TimedOut),
+            Read::Rejected(status) => return Ok(Read::Rejected(status)),
+        }
+    }
+}
+// Checks a complete request head or the part of it that was read so far
+// Lines end with a LF, the CR before it is missing in heads of the lenient mode
+pub fn check_head(head: &[u8], limits: &Limits) -> Result<(), StatusCode> {
+    let line_end = head.iter().position(|byte| *byte == b'\n');
+    let line_len = match line_end {
+        Some(end) if head[..end].ends_with(b"\r") => end - 1,
+        Some(end) => end,
+        None => head.len(),
+    };
+    if exceeds(line_len, limits.request_line) {
+        return Err(StatusCode::URI_TOO_LONG);
+    }
+    let headers = match line_end {
+        Some(end) => &head[end + 1..],
+        None => return Ok(()),
+    };
+    if exceeds(headers.len(), limits.header_bytes) {
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    // every header line ends with a line break, a complete head has one more for the empty line
+    let mut count = headers.iter().filter(|byte| **byte == b'\n').count();
+    if matches!(headers, b"\r\n" | b"\n")
+        || headers.ends_with(b"\n\n")
+        || headers.ends_with(b"\n\r\n")
+    {
+        count -= 1;
+    }
+    if exceeds(count, limits.header_count) {
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    Ok(())
+}
+// Length of the head up to and including the empty line that ends it. In lenient mode the
+// lines may end with a bare LF
+fn find_head_end(buf: &[u8], mode: ParseMode) -> Option<usize> {
+    match mode {
+        ParseMode::Strict => buf
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|pos| pos + 4),
+        ParseMode::Lenient => {
+            buf.iter()
+                .enumerate()
+                .find_map(|(pos, byte)| match (byte, &buf[pos + 1..]) {
+                    (b'\n', [b'\n', ..]) => Some(pos + 2),
+                    (b'\n', [b'\r', b'\n', ..]) => Some(pos + 3),
+                    _ => None,
+                })
+        }
+    }
+}
+
+// Reads a body of len bytes, buf may already hold the start of it.
+// Gives up at the deadline or as soon as the client falls below min_rate bytes per second
+pub async fn read_body<S: Connection>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    len: usize,
+    deadline: Option<Instant>,
+    min_rate: Option<u64>,
+) -> std::io::Result<Read<BytesMut>> {
+    let started = Instant::now();
+    let already_read = buf.len();
+    while buf.len() < len {
+        let received = (buf.len() - already_read) as f64;
+        // the latest point in time at which the bytes received so far still meet the rate
+        let rate_deadline = min_rate.filter(|rate| *rate > 0).map(|rate| {
+            started + BODY_RATE_GRACE + Duration::from_secs_f64(received / rate as f64)
+        });
+        match read_more(socket, buf, earliest(deadline, rate_deadline)).await? {
+            Read::Done(()) => (),
+            Read::Closed => return Ok(Read::Closed),
+            Read::TimedOut => return Ok(Read::TimedOut),
+            Read::Rejected(status) => return Ok(Read::Rejected(status)),
+        }
+    }
+    Ok(Read::Done(buf.split_to(len)))
+}
+
+// The body of a request whose client waits for a 100 Continue before sending it. Nothing is
+// read until the handler asks for it with NewRequestType::read_body, the 100 goes out then.
+// A handler that rejects the request first, e.g. because of auth, answers without the body
+// being sent at all
+#[derive(Clone, Default)]
+pub struct PendingBody {
+    wanted: Arc<Mutex<Option<oneshot::Sender<oneshot::Sender<BytesMut>>>>>,
+}
+// The connection side of a PendingBody, resolves once the handler wants the body
+pub type BodyWanted = oneshot::Receiver<oneshot::Sender<BytesMut>>;
+impl PendingBody {
+    pub fn channel() -> (PendingBody, BodyWanted) {
+        let (wanted, body_wanted) = oneshot::channel();
+        let pending = PendingBody {
+            wanted: Arc::new(Mutex::new(Some(wanted))),
+        };
+        (pending, body_wanted)
+    }
+    // Fails if the body was already read through a clone of the request or the connection
+    // is gone
+    pub async fn read(&self) -> std::io::Result<BytesMut> {
+        let wanted = match self.wanted.lock() {
+            Ok(mut wanted) => wanted.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        let wanted = match wanted {
+            Some(wanted) => wanted,
+            None => return Err(std::io::Error::other("the body was already read")),
+        };
+        let (reply, body) = oneshot::channel();
+        if wanted.send(reply).is_err() {
+            return Err(std::io::ErrorKind::ConnectionAborted.into());
+        }
+        body.await
+            .map_err(|_| std::io::ErrorKind::ConnectionAborted.into())
+    }
+}
+impl std::fmt::Debug for PendingBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PendingBody")
+    }
+}
+
+// Adds a header to a response that was already serialized by IntoResp
+pub fn with_header(mut response: Vec<u8>, header: &str) -> Vec<u8> {
+    let status_line_end = match response.windows(2).position(|window| window == b"\r\n") {
+        Some(pos) => pos + 2,
+        None => return response,
+    };
+    let header = header.to_string() + "\r\n";
+    response.splice(status_line_end..status_line_end, header.into_bytes());
+    response
+}
+
+// The IntoResp impls always write HTTP/1.1, HTTP/1.0 clients get their own version back
+pub fn with_version(mut response: Vec<u8>, version: Version) -> Vec<u8> {
+    if version == Version::HTTP_10 && response.starts_with(b"HTTP/1.1 ") {
+        response[..8].copy_from_slice(b"HTTP/1.0");
+    }
+    response
+}
+pub fn has_content_length(response: &[u8]) -> bool {
+    let head_end = find_head_end(response, ParseMode::Strict).unwrap_or(response.len());
+    response[..head_end]
+        .split(|byte| *byte == b'\n')
+        .any(|line| line.len() >= 15 && line[..15].eq_ignore_ascii_case(b"content-length:"))
+}
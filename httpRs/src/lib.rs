@@ -1,11 +1,28 @@
 #![forbid(unsafe_code)]
+// The route attributes refer to this crate as ::httpRs, which has to work in here as well
+extern crate self as httpRs;
+pub use http;
+pub use httpRs_macros::{delete, get, head, options, patch, post, put, routes};
+pub use serde_json;
+pub mod config;
+pub mod conn;
 pub mod h2;
 pub mod parse;
+#[cfg(unix)]
+pub mod privileges;
 pub mod request;
 pub mod response;
+#[cfg(unix)]
+pub mod restart;
 pub mod router;
+pub mod server;
+pub mod state;
+#[cfg(unix)]
+pub mod systemd;
 pub mod tls;
 pub mod types;
+#[cfg(unix)]
+pub mod unix;
 #[cfg(test)]
 mod tests {
 
@@ -14,6 +31,1597 @@ mod tests {
 
     use crate::parse::parse_new_method_line;
 
+    use crate::parse::NewRequestType;
+    use crate::response::respond;
+    use crate::router::{Handler, HandlerResponse, Router};
+    use crate::state::{FromRef, State};
+
+    #[derive(Debug)]
+    struct AppState {
+        db: DbPool,
+    }
+    #[derive(Debug, Clone)]
+    struct DbPool(&'static str);
+    impl FromRef<AppState> for DbPool {
+        fn from_ref(input: &AppState) -> Self {
+            input.db.clone()
+        }
+    }
+    fn db_handler(_req: NewRequestType, state: State<DbPool>) -> HandlerResponse<'static> {
+        Box::pin(async move { respond(state.0 .0) })
+    }
+    async fn call<T: Send + Sync + 'static>(router: &Router<T>, path: &str) -> Option<Vec<u8>> {
+        let res = router.routes.get_handler(path.to_string())?;
+        let resp = res
+            .handler
+            .handle(
+                NewRequestType::default(),
+                router.routes.state.clone(),
+                res.extract,
+            )
+            .await?;
+        Some(resp.into_response())
+    }
+
+    #[tokio::test]
+    async fn substate_handler() {
+        let router = Router::new()
+            .add_handler(
+                "/db",
+                Handler::from_substate(Handler::WithState(db_handler)),
+            )
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+        let resp = call(&router, "/db").await.unwrap();
+        assert!(resp.ends_with(b"\r\n\r\npool"));
+    }
+
+    #[tokio::test]
+    async fn nested_router_state() {
+        let derived = Router::<DbPool>::new()
+            .add_handler("/derived", Handler::WithState(db_handler))
+            .unwrap();
+        let own = Router::<DbPool>::new()
+            .add_handler("/own", Handler::WithState(db_handler))
+            .unwrap()
+            .with_state(DbPool("own pool"));
+        let router = Router::new()
+            .nest("/admin", derived)
+            .unwrap()
+            .nest_with_own_state("/module", own)
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+
+        let resp = call(&router, "/admin/derived").await.unwrap();
+        assert!(resp.ends_with(b"\r\n\r\npool"));
+        let resp = call(&router, "/module/own").await.unwrap();
+        assert!(resp.ends_with(b"\r\n\r\nown pool"));
+
+        // AppState is not Clone, the nested router shares the state of its parent
+        let same = Router::<AppState>::new()
+            .add_handler(
+                "/same",
+                Handler::from_substate(Handler::WithState(db_handler)),
+            )
+            .unwrap();
+        let router = Router::new()
+            .nest_with_own_state("/module", same)
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+        let resp = call(&router, "/module/same").await.unwrap();
+        assert!(resp.ends_with(b"\r\n\r\npool"));
+
+        let inner = Router::<Counted>::new()
+            .add_handler("/counted", Handler::WithState(counted_handler))
+            .unwrap();
+        let clones = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let router = Router::new()
+            .nest("/inner", inner)
+            .unwrap()
+            .with_state(Counted(std::sync::Arc::clone(&clones)));
+        for _ in 0..3 {
+            let resp = call(&router, "/inner/counted").await.unwrap();
+            assert!(resp.ends_with(b"\r\n\r\ncounted"));
+        }
+        assert_eq!(clones.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        let derived = Router::<DbPool>::new()
+            .add_handler("/derived", Handler::WithState(db_handler))
+            .unwrap();
+        assert_eq!(
+            Router::<AppState>::new()
+                .add_handler("/admin/derived", Handler::Without(hello_handler))
+                .unwrap()
+                .nest("/admin", derived)
+                .err(),
+            Some(crate::router::RouteError::DuplicateRoute(
+                "/admin/derived".to_string()
+            ))
+        );
+    }
+
+    // Counts how often it is cloned
+    struct Counted(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Counted(std::sync::Arc::clone(&self.0))
+        }
+    }
+    fn counted_handler(_req: NewRequestType, _state: State<Counted>) -> HandlerResponse<'static> {
+        Box::pin(async move { respond("counted") })
+    }
+
+    // A routing tree can be served without a Router, it is dropped with the server
+    #[tokio::test]
+    async fn serve_node() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let state = State::new(AppState { db: DbPool("pool") });
+        let mut node = crate::router::Node::new("/");
+        let mut node = node
+            .add_handler(
+                "/db",
+                Handler::from_substate(Handler::WithState(db_handler)),
+            )
+            .unwrap();
+        node.state = Some(state.clone());
+        let server = node
+            .make_into_serveable()
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server.serve());
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /db HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("\r\n\r\npool"), "{resp}");
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+        assert_eq!(std::sync::Arc::strong_count(&state.0), 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_request_gets_400() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let router: Router<AppState> = Router::new();
+
+        client.write_all(b"NOT A REQUEST\r\n\r\n").await.unwrap();
+        crate::router::handle_conn_node_based(
+            socket,
+            &crate::router::RouteTable::new(router),
+            &crate::server::ServerHandle::new(),
+        )
+        .await
+        .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn transient_accept_errors() {
+        use crate::router::is_transient_accept_error;
+        use std::io::{Error, ErrorKind};
+        #[cfg(unix)]
+        for errno in [
+            nix::errno::Errno::ENFILE,
+            nix::errno::Errno::EMFILE,
+            nix::errno::Errno::ENOBUFS,
+            nix::errno::Errno::ENOMEM,
+        ] {
+            assert!(is_transient_accept_error(&Error::from_raw_os_error(
+                errno as i32
+            )));
+        }
+        assert!(is_transient_accept_error(&Error::from(
+            ErrorKind::ConnectionAborted
+        )));
+        assert!(!is_transient_accept_error(&Error::from(
+            ErrorKind::PermissionDenied
+        )));
+    }
+
+    fn draining_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            respond("done")
+        })
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_drains_connections() {
+        use crate::server::ServerHandle;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let server = Router::<AppState>::new()
+            .add_handler("/", Handler::Without(draining_handler))
+            .unwrap()
+            .make_into_serveable()
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_handle(ServerHandle::with_grace_period(Duration::from_secs(5)));
+        let addr = server.local_addr().unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server.serve());
+
+        let mut in_flight = tokio::net::TcpStream::connect(addr).await.unwrap();
+        in_flight
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        handle.shutdown();
+
+        let mut resp = String::new();
+        in_flight.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("\r\n\r\ndone"), "{resp}");
+        let mut resp = Vec::new();
+        idle.read_to_end(&mut resp).await.unwrap();
+        assert!(resp.is_empty());
+        running.await.unwrap().unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    fn hello_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move { respond("hello") })
+    }
+
+    #[tokio::test]
+    async fn serve_on_port_zero() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .make_into_serveable()
+            .with_listener(listener);
+        let addr = server.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        let handle = server.handle();
+        assert_eq!(handle.local_addr(), Some(addr));
+        let running = tokio::spawn(server.serve());
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("\r\n\r\nhello"));
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    fn slow_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            respond("slow")
+        })
+    }
+
+    async fn spawn_router(
+        router: Router<AppState>,
+    ) -> (
+        std::net::SocketAddr,
+        crate::server::ServerHandle,
+        tokio::task::JoinHandle<std::io::Result<()>>,
+    ) {
+        let server = router
+            .make_into_serveable()
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.handle();
+        (addr, handle, tokio::spawn(server.serve()))
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        use crate::config::{RouteConfig, ServerConfig};
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let config = ServerConfig::new()
+            .header_timeout(Duration::from_millis(100))
+            .body_timeout(Duration::from_millis(100));
+        let router = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .add_handler_with_config(
+                "/slow",
+                Handler::Without(slow_handler),
+                RouteConfig::new()
+                    .handler_timeout(Duration::from_millis(50))
+                    .handler_timeout_status(http::StatusCode::GATEWAY_TIMEOUT),
+            )
+            .unwrap()
+            .with_config(config);
+        let (addr, handle, running) = spawn_router(router).await;
+
+        // never sends anything
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        // headers never finish
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        // body is shorter than announced
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"POST /hello HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nab")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_alive() {
+        use crate::config::ServerConfig;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .with_config(ServerConfig::new().keep_alive_timeout(Duration::from_millis(100)));
+        let (addr, handle, running) = spawn_router(router).await;
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let len = client.read(&mut buf).await.unwrap();
+            assert!(buf[..len].ends_with(b"\r\n\r\nhello"));
+        }
+        // the connection is closed once it was idle for too long
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).await.unwrap();
+        assert!(resp.is_empty());
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.contains("\r\nConnection: close\r\n"));
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn size_limits() {
+        use crate::config::{Limits, RouteConfig, ServerConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .add_handler_with_config(
+                "/small",
+                Handler::Without(hello_handler),
+                RouteConfig::new().limits(Limits::new().body(4)),
+            )
+            .unwrap()
+            .with_config(
+                ServerConfig::new().limits(Limits::new().request_line(32).header_count(3)),
+            );
+        let (addr, handle, running) = spawn_router(router).await;
+        let cases: [(&[u8], &str); 4] = [
+            (
+                b"GET /hello?a=aaaaaaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                "HTTP/1.1 414 URI Too Long\r\n",
+            ),
+            (
+                b"GET /hello HTTP/1.1\r\nHost: localhost\r\nA: a\r\nB: b\r\nC: c\r\n\r\n",
+                "HTTP/1.1 431 Request Header Fields Too Large\r\n",
+            ),
+            // rejected without waiting for the body
+            (
+                b"POST /small HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n",
+                "HTTP/1.1 413 Payload Too Large\r\n",
+            ),
+            (
+                b"POST /hello HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+                "HTTP/1.1 200 OK\r\n",
+            ),
+        ];
+        for (req, status_line) in cases {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(req).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.starts_with(status_line), "{resp}");
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn expect_continue() {
+        use crate::config::{Limits, RouteConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .add_handler("/upload", Handler::Without(guarded_handler))
+            .unwrap()
+            .add_handler_with_config(
+                "/small",
+                Handler::Without(hello_handler),
+                RouteConfig::new().limits(Limits::new().body(4)),
+            )
+            .unwrap();
+        let (addr, handle, running) = spawn_router(router).await;
+
+        // the 100 goes out once the handler reads the body
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic eA==\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n")
+            .await
+            .unwrap();
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        client.write_all(b"hello").await.unwrap();
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let mut resp = vec![0; expected.len()];
+        client.read_exact(&mut resp).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&resp),
+            String::from_utf8_lossy(expected)
+        );
+        // the body was read, so the connection is kept alive
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("\r\n\r\nhello"), "{resp}");
+
+        // the final status is sent right away, without a 100 and without waiting for the body
+        let cases = [
+            ("/upload", "100-continue", "HTTP/1.1 401 Unauthorized\r\n"),
+            ("/hello", "100-continue", "HTTP/1.1 200 OK\r\n"),
+            ("/missing", "100-continue", "HTTP/1.1 404 Not Found\r\n"),
+            (
+                "/small",
+                "100-continue",
+                "HTTP/1.1 413 Payload Too Large\r\n",
+            ),
+            (
+                "/hello",
+                "something-else",
+                "HTTP/1.1 417 Expectation Failed\r\n",
+            ),
+        ];
+        for (path, expect, status_line) in cases {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req =
+                format!("POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nExpect: {expect}\r\n\r\n");
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.starts_with(status_line), "{resp}");
+            assert!(resp.contains("Connection: close\r\n"), "{resp}");
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    fn echo_handler(mut req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move {
+            let body = match req.read_body().await {
+                Ok(body) => body.map(|body| body.to_vec()).unwrap_or_default(),
+                Err(_) => return respond(http::StatusCode::BAD_REQUEST),
+            };
+            respond((http::StatusCode::OK, body))
+        })
+    }
+
+    // Rejects requests without credentials before it looks at the body
+    fn guarded_handler(req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move {
+            if !req.headers.contains_key("authorization") {
+                return respond(http::StatusCode::UNAUTHORIZED);
+            }
+            echo_handler(req).await
+        })
+    }
+
+    #[tokio::test]
+    async fn pipelining() {
+        use crate::config::{ParseMode, ServerConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        for mode in [ParseMode::Strict, ParseMode::Lenient] {
+            let router = Router::<AppState>::new()
+                .add_handler("/hello", Handler::Without(hello_handler))
+                .unwrap()
+                .add_handler("/echo", Handler::Without(echo_handler))
+                .unwrap()
+                .with_config(ServerConfig::new().parse_mode(mode));
+            let (addr, handle, running) = spawn_router(router).await;
+
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(
+                    b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirst\
+                      GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                      POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nthird\
+                      GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            let bodies: Vec<&str> = resp
+                .split("HTTP/1.1 200 OK\r\n")
+                .skip(1)
+                .map(|resp| resp.split_once("\r\n\r\n").unwrap().1)
+                .collect();
+            assert_eq!(bodies, ["first", "hello", "third", "hello"], "{resp}");
+
+            // legacy clients that end lines with a bare LF are only served in lenient mode
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(
+                    b"POST /echo HTTP/1.1\nHost: localhost\nContent-Length: 5\n\nfirst\
+                      GET /hello HTTP/1.1\nHost: localhost\nConnection: close\n\n",
+                )
+                .await
+                .unwrap();
+            let mut resp = String::new();
+            let limit = std::time::Duration::from_secs(5);
+            tokio::time::timeout(limit, client.read_to_string(&mut resp))
+                .await
+                .unwrap()
+                .unwrap();
+            let bodies: Vec<&str> = resp
+                .split("HTTP/1.1 200 OK\r\n")
+                .skip(1)
+                .map(|resp| resp.split_once("\r\n\r\n").unwrap().1)
+                .collect();
+            match mode {
+                ParseMode::Strict => {
+                    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{resp}")
+                }
+                ParseMode::Lenient => assert_eq!(bodies, ["first", "hello"], "{resp}"),
+            }
+            handle.shutdown();
+            running.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn http_versions() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap();
+        let (addr, handle, running) = spawn_router(router).await;
+
+        // closed after the response by default
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.0 200 OK\r\nConnection: close\r\n"));
+
+        // kept alive when asked for
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let len = client.read(&mut buf).await.unwrap();
+        let resp = String::from_utf8_lossy(&buf[..len]);
+        assert!(resp.starts_with("HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n"));
+        assert!(resp.ends_with("\r\n\r\nhello"));
+        client
+            .write_all(b"GET /hello HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/2.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    fn id_handler(
+        _req: NewRequestType,
+        _state: State<AppState>,
+        extracts: std::collections::HashMap<String, String>,
+    ) -> HandlerResponse<'static> {
+        Box::pin(async move { respond((http::StatusCode::OK, extracts["id"].clone())) })
+    }
+
+    #[tokio::test]
+    async fn normalized_routing() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = Router::new()
+            .add_handler("/user/:id", Handler::WithStateAndExtract(id_handler))
+            .unwrap()
+            .add_handler("/robots.txt", Handler::Without(hello_handler))
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+        let (addr, handle, running) = spawn_router(router).await;
+        let cases = [
+            ("/a/./b/../../user/j%C3%B6rg", "200 OK", "jörg"),
+            ("/user/a%2Fb", "200 OK", "a/b"),
+            ("/robots.txt", "200 OK", "hello"),
+            ("/user/%zz", "400 Bad Request", ""),
+        ];
+        for (path, status, body) in cases {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req =
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(
+                resp.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "{resp}"
+            );
+            assert!(resp.ends_with(&format!("\r\n\r\n{body}")), "{resp}");
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_policy() {
+        use crate::config::{ServerConfig, TrailingSlash};
+        use crate::router::Routed;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = || {
+            Router::<AppState>::new()
+                .add_handler("/users", Handler::Without(hello_handler))
+                .unwrap()
+                .add_handler("/teams/", Handler::Without(hello_handler))
+                .unwrap()
+                .add_handler("/user/:id", Handler::Without(hello_handler))
+                .unwrap()
+        };
+        let routes = router().routes;
+        let found = |path, policy| matches!(routes.route(path, policy), Routed::Found(_));
+        assert!(found("/users", TrailingSlash::Strict));
+        assert!(!found("/users/", TrailingSlash::Strict));
+        assert!(!found("/teams", TrailingSlash::Strict));
+        assert!(!found("/user/5/", TrailingSlash::Strict));
+        assert!(found("/users/", TrailingSlash::Permissive));
+        assert!(found("/teams", TrailingSlash::Permissive));
+        assert!(found("/user/5/", TrailingSlash::Permissive));
+        // both forms can be registered as different routes
+        let both = router()
+            .add_handler("/users/", Handler::Without(hello_handler))
+            .unwrap();
+        match both.routes.route("/users/", TrailingSlash::Strict) {
+            Routed::Found(res) => assert_eq!(res.route, "/users/"),
+            _ => panic!("/users/ not found"),
+        }
+
+        let redirecting =
+            router().with_config(ServerConfig::new().trailing_slash(TrailingSlash::Redirect));
+        let (addr, handle, running) = spawn_router(redirecting).await;
+        for (path, location) in [("/users/?page=2", "/users?page=2"), ("/teams", "/teams/")] {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req =
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(
+                resp.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"),
+                "{resp}"
+            );
+            assert!(
+                resp.contains(&format!("\r\nLocation:{location}\r\n")),
+                "{resp}"
+            );
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+
+        // targets without a path are not routed, not even to / or a redirect to it
+        for policy in [TrailingSlash::Redirect, TrailingSlash::Permissive] {
+            let router = router()
+                .add_handler("/", Handler::Without(hello_handler))
+                .unwrap()
+                .with_config(ServerConfig::new().trailing_slash(policy));
+            let (addr, handle, running) = spawn_router(router).await;
+            for target in ["CONNECT example.com:443", "OPTIONS *"] {
+                let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let req =
+                    format!("{target} HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n");
+                client.write_all(req.as_bytes()).await.unwrap();
+                let mut resp = String::new();
+                client.read_to_string(&mut resp).await.unwrap();
+                assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "{resp}");
+            }
+            handle.shutdown();
+            running.await.unwrap().unwrap();
+        }
+    }
+
+    fn api_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move { respond("api") })
+    }
+    fn subdomain_handler(
+        _req: NewRequestType,
+        _state: State<AppState>,
+        extracts: std::collections::HashMap<String, String>,
+    ) -> HandlerResponse<'static> {
+        Box::pin(async move { respond((http::StatusCode::OK, extracts["subdomain"].clone())) })
+    }
+
+    #[tokio::test]
+    async fn virtual_hosts() {
+        use crate::router::RouteError;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let api = Router::new()
+            .add_handler("/", Handler::Without(api_handler))
+            .unwrap();
+        let tenants = Router::new()
+            .add_handler("/whoami", Handler::WithStateAndExtract(subdomain_handler))
+            .unwrap();
+        let router = Router::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .host("api.example.com", api)
+            .unwrap()
+            .host("*.tenant.example.com", tenants)
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+        for pattern in ["", "a*.b.com", "*b.com", "*.*.b.com", "b.com:8080"] {
+            assert_eq!(
+                Router::<AppState>::new().host(pattern, Router::new()).err(),
+                Some(RouteError::InvalidHost(pattern.to_string()))
+            );
+        }
+        let nested = Router::<AppState>::new()
+            .host("inner.example.com", Router::new())
+            .unwrap();
+        assert_eq!(
+            Router::<AppState>::new()
+                .host("outer.example.com", nested)
+                .err(),
+            Some(RouteError::NestedHost("inner.example.com".to_string()))
+        );
+        let (addr, handle, running) = spawn_router(router).await;
+        for (req, expected) in [
+            (
+                "GET / HTTP/1.1\r\nHost: api.example.com:8080",
+                "\r\n\r\napi",
+            ),
+            ("GET / HTTP/1.1\r\nHost: API.Example.com", "\r\n\r\napi"),
+            (
+                "GET /whoami HTTP/1.1\r\nHost: acme.tenant.example.com",
+                "\r\n\r\nacme",
+            ),
+            // the wildcard needs a subdomain, the request goes to the default routes
+            (
+                "GET /whoami HTTP/1.1\r\nHost: tenant.example.com",
+                "HTTP/1.1 404 Not Found\r\n",
+            ),
+            ("GET /hello HTTP/1.1\r\nHost: other.com", "\r\n\r\nhello"),
+            ("GET /hello HTTP/1.1", "HTTP/1.1 400 Bad Request\r\n"),
+            ("GET /hello HTTP/1.0", "\r\n\r\nhello"),
+        ] {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req = format!("{req}\r\nConnection: close\r\n\r\n");
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(
+                resp.starts_with(expected) || resp.ends_with(expected),
+                "{req} {resp}"
+            );
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn named_routes() {
+        use crate::config::TrailingSlash;
+        use crate::router::{RouteError, Routed, UrlError};
+        let admin = Router::<AppState>::new()
+            .add_named("admin_user", "/user/:id", Handler::Without(hello_handler))
+            .unwrap();
+        let router = Router::<AppState>::new()
+            .add_named("user_show", "/user/:id", Handler::Without(hello_handler))
+            .unwrap()
+            .add_named(
+                "user_post",
+                "/user/:id/post/:post",
+                Handler::Without(hello_handler),
+            )
+            .unwrap()
+            .nest_with_own_state("/admin", admin)
+            .unwrap();
+        // paths that could never match a request are an error instead of a panic
+        for path in ["/a/../b", "/a/./b", "/a/{id}"] {
+            assert_eq!(
+                Router::<AppState>::new()
+                    .add_named("invalid", path, Handler::Without(hello_handler))
+                    .err(),
+                Some(RouteError::InvalidPath(path.to_string()))
+            );
+            assert_eq!(
+                Router::<AppState>::new()
+                    .add_method_handler(
+                        crate::types::Method::GET,
+                        path,
+                        Handler::Without(hello_handler)
+                    )
+                    .err(),
+                Some(RouteError::InvalidPath(path.to_string()))
+            );
+        }
+        // every host has its own names
+        let host = |path| {
+            Router::<AppState>::new()
+                .add_named("home", path, Handler::Without(hello_handler))
+                .unwrap()
+        };
+        let router = router
+            .host("api.example.com", host("/v1"))
+            .unwrap()
+            .host("*.tenant.example.com", host("/"))
+            .unwrap();
+        assert_eq!(
+            router.url_for_host("api.example.com", "home", &[]),
+            Ok("/v1".to_string())
+        );
+        assert_eq!(
+            router.url_for_host("*.Tenant.example.com", "home", &[]),
+            Ok("/".to_string())
+        );
+        assert_eq!(
+            router.url_for("home", &[]),
+            Err(UrlError::UnknownRoute("home".to_string()))
+        );
+        let listed = router.routes();
+        let api_home = listed
+            .iter()
+            .find(|info| info.host.as_deref() == Some("api.example.com"))
+            .unwrap();
+        assert_eq!(api_home.name.as_deref(), Some("home"));
+
+        let mut router = router;
+        assert_eq!(
+            router
+                .add_named("user_show", "/other", Handler::Without(hello_handler))
+                .err(),
+            Some(RouteError::DuplicateName("user_show".to_string()))
+        );
+        let clashing = Router::<AppState>::new()
+            .add_named("user_show", "/user/:id", Handler::Without(hello_handler))
+            .unwrap();
+        assert_eq!(
+            Router::<AppState>::new()
+                .add_named("user_show", "/user/:id", Handler::Without(hello_handler))
+                .unwrap()
+                .nest_with_own_state("/old", clashing)
+                .err(),
+            Some(RouteError::DuplicateName("user_show".to_string()))
+        );
+        assert_eq!(
+            router.url_for("user_show", &[("id", "42")]),
+            Ok("/user/42".to_string())
+        );
+        assert_eq!(
+            router.url_for("user_post", &[("post", "7"), ("id", "42")]),
+            Ok("/user/42/post/7".to_string())
+        );
+        assert_eq!(
+            router.url_for("admin_user", &[("id", "1")]),
+            Ok("/admin/user/1".to_string())
+        );
+        assert_eq!(
+            router.url_for("missing", &[]),
+            Err(UrlError::UnknownRoute("missing".to_string()))
+        );
+        assert_eq!(
+            router.url_for("user_show", &[]),
+            Err(UrlError::MissingParam("id".to_string()))
+        );
+        assert_eq!(
+            router.url_for("user_show", &[("id", "1"), ("page", "2")]),
+            Err(UrlError::ExtraParam("page".to_string()))
+        );
+        assert_eq!(
+            router.url_for("user_show", &[("id", "..")]),
+            Err(UrlError::InvalidValue("id".to_string()))
+        );
+
+        // the value comes back unchanged from the extracts of the route
+        let url = router.url_for("user_show", &[("id", "a/b c?")]).unwrap();
+        assert_eq!(url, "/user/a%2Fb%20c%3F");
+        let path = crate::parse::normalize_path(&url, false).unwrap();
+        match router.routes.route(&path, TrailingSlash::Strict) {
+            Routed::Found(res) => assert_eq!(
+                crate::parse::percent_decode(&res.extract.unwrap()["id"]),
+                "a/b c?"
+            ),
+            _ => panic!("{url} not found"),
+        }
+    }
+
+    #[tokio::test]
+    async fn route_listing() {
+        use crate::router::RouteInfo;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let api = Router::new()
+            .add_handler("/", Handler::Without(api_handler))
+            .unwrap();
+        let router = Router::<AppState>::new()
+            .add_named("user_show", "/user/:id", Handler::Without(hello_handler))
+            .unwrap()
+            .add_handler(
+                "/db",
+                Handler::from_substate(Handler::WithState(db_handler)),
+            )
+            .unwrap()
+            .host("api.example.com", api)
+            .unwrap()
+            .route_listing("/__routes")
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+        let info = |path: &str, host: Option<&str>, name: Option<&str>, handler: &str| RouteInfo {
+            path: path.to_string(),
+            host: host.map(str::to_string),
+            name: name.map(str::to_string),
+            method: "*".to_string(),
+            handler: handler.to_string(),
+            middleware: Vec::new(),
+        };
+        let expected = vec![
+            info("/__routes", None, None, "Erased"),
+            info("/db", None, None, "Erased"),
+            info("/user/:id", None, Some("user_show"), "Without"),
+            info("/", Some("api.example.com"), None, "Without"),
+        ];
+        assert_eq!(router.routes(), expected);
+
+        let (addr, handle, running) = spawn_router(router).await;
+        for accept in ["application/json", "text/html"] {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req = format!(
+                "GET /__routes HTTP/1.1\r\nHost: localhost\r\nAccept: {accept}\r\nConnection: close\r\n\r\n"
+            );
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+            assert!(head.contains(&format!("Content-type: {accept}")), "{resp}");
+            match accept {
+                "text/html" => assert!(body.contains("<td>/user/:id</td>"), "{body}"),
+                _ => {
+                    let listed: serde_json::Value = serde_json::from_str(body).unwrap();
+                    assert_eq!(listed.as_array().unwrap().len(), 4);
+                    assert_eq!(listed[2]["name"], "user_show");
+                }
+            }
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    fn old_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            respond("old")
+        })
+    }
+    fn new_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move { respond("new") })
+    }
+
+    #[tokio::test]
+    async fn swap_routes() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let served = Router::<AppState>::new()
+            .add_handler("/work", Handler::Without(old_handler))
+            .unwrap()
+            .make_into_serveable();
+        let table = served.route_table();
+        let server = served.bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server.serve());
+
+        let mut kept_alive = tokio::net::TcpStream::connect(addr).await.unwrap();
+        kept_alive
+            .write_all(b"GET /work HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let old = table.swap(
+            Router::new()
+                .add_handler("/work", Handler::Without(new_handler))
+                .unwrap()
+                .add_handler("/feature", Handler::Without(new_handler))
+                .unwrap(),
+        );
+        assert!(old.routes.find("/work").is_some());
+
+        // the request that was already running finishes on the old router
+        let mut resp = vec![0; 1024];
+        let len = kept_alive.read(&mut resp).await.unwrap();
+        assert!(resp[..len].ends_with(b"\r\n\r\nold"));
+        // the next request on the same connection and new connections use the new one
+        for path in ["/work", "/feature"] {
+            let req = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            kept_alive.write_all(req.as_bytes()).await.unwrap();
+            let len = kept_alive.read(&mut resp).await.unwrap();
+            assert!(resp[..len].ends_with(b"\r\n\r\nnew"));
+        }
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /feature HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("\r\n\r\nnew"));
+        drop(kept_alive);
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[crate::get("/item/:id/:name")]
+    async fn show_item(
+        state: State<AppState>,
+        id: u32,
+        name: String,
+    ) -> (http::StatusCode, String) {
+        (http::StatusCode::OK, format!("{} {id} {name}", state.db.0))
+    }
+    #[crate::get("/items")]
+    async fn list_items() -> &'static str {
+        "items"
+    }
+    #[crate::post("/items")]
+    async fn create_item(req: NewRequestType) -> (http::StatusCode, String) {
+        let body = req.body.map(|body| body.to_vec()).unwrap_or_default();
+        (
+            http::StatusCode::CREATED,
+            String::from_utf8_lossy(&body).to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn route_attributes() {
+        use crate::router::RouteError;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let router = Router::new()
+            .mount(crate::routes![show_item, list_items, create_item])
+            .unwrap()
+            .with_state(AppState { db: DbPool("pool") });
+        let methods: Vec<_> = router
+            .routes()
+            .into_iter()
+            .map(|info| (info.method, info.path))
+            .collect();
+        assert!(methods.contains(&("GET".to_string(), "/items".to_string())));
+        assert!(methods.contains(&("POST".to_string(), "/items".to_string())));
+        let mut again = Router::<AppState>::new()
+            .mount(crate::routes![list_items])
+            .unwrap();
+        assert_eq!(
+            list_items(again).err(),
+            Some(RouteError::DuplicateRoute("GET /items".to_string()))
+        );
+        again = Router::new()
+            .add_handler("/items", Handler::Without(hello_handler))
+            .unwrap();
+        assert_eq!(
+            create_item(again).err(),
+            Some(RouteError::DuplicateRoute("/items".to_string()))
+        );
+        let (addr, handle, running) = spawn_router(router).await;
+        for (req, status, body) in [
+            ("GET /item/7/box", "200 OK", "pool 7 box"),
+            ("GET /item/x/box", "400 Bad Request", ""),
+            ("POST /item/7/box", "405 Method Not Allowed", ""),
+            ("POST /items", "201 Created", "hello"),
+            ("GET /items", "200 OK", "items"),
+            ("PUT /items", "405 Method Not Allowed", ""),
+        ] {
+            let allow = match req {
+                "PUT /items" => "GET, POST",
+                _ => "GET",
+            };
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req = format!(
+                "{req} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+            );
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(
+                resp.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "{resp}"
+            );
+            assert!(resp.ends_with(&format!("\r\n\r\n{body}")), "{resp}");
+            if status.starts_with("405") {
+                assert!(resp.contains(&format!("\r\nAllow:{allow}\r\n")), "{resp}");
+            }
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    // Handlers that do not fit their path are rejected when they are compiled
+    #[test]
+    fn route_attribute_errors() {
+        trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+    }
+
+    #[cfg(unix)]
+    fn peer_handler(req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move {
+            match req.peer_credentials {
+                Some(cred) => respond((http::StatusCode::OK, format!("uid {}", cred.uid))),
+                None => respond("unknown"),
+            }
+        })
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let path = std::env::temp_dir().join(format!("httprs-test-{}.sock", std::process::id()));
+        // a stale socket file from a previous run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = Router::<AppState>::new()
+            .add_handler("/peer", Handler::Without(peer_handler))
+            .unwrap()
+            .make_into_serveable()
+            .bind_unix(&path, Some(0o660))
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        let handle = server.handle();
+        let running = tokio::spawn(server.serve());
+
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        client
+            .write_all(b"GET /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        let uid = client.peer_cred().unwrap().uid();
+        assert!(resp.ends_with(&format!("\r\n\r\nuid {uid}")));
+
+        // the socket is in use so it can not be bound again
+        let err = crate::unix::bind_unix_listener(&path, None).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_multiple_listeners() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let server = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .make_into_serveable()
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addrs = server.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        let handle = server.handle();
+        assert_eq!(handle.local_addrs(), addrs);
+        let running = tokio::spawn(server.serve());
+
+        for addr in addrs.iter() {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.ends_with("\r\n\r\nhello"));
+        }
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+        for addr in addrs.iter() {
+            assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn socket_activation() {
+        use crate::systemd::{ActivatedListener, ActivatedSocket};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        // taking them from the environment is only allowed before the runtime is started
+        assert!(crate::systemd::listeners_from_env().is_err());
+        // a tls listener handed over by serve_with_hot_restart is not served without tls
+        let https = vec![ActivatedListener {
+            name: Some("https".to_string()),
+            socket: ActivatedSocket::Tcp(std::net::TcpListener::bind("127.0.0.1:0").unwrap()),
+        }];
+        let err = Router::<AppState>::new()
+            .make_into_serveable()
+            .bind_activated(https)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = std_listener.local_addr().unwrap();
+        let activated = vec![ActivatedListener {
+            name: Some("http".to_string()),
+            socket: ActivatedSocket::Tcp(std_listener),
+        }];
+
+        let server = Router::<AppState>::new()
+            .add_handler("/hello", Handler::Without(hello_handler))
+            .unwrap()
+            .make_into_serveable()
+            .bind_activated(activated)
+            .unwrap();
+        assert_eq!(server.local_addr().unwrap(), addr);
+        let handle = server.handle();
+        let running = tokio::spawn(server.serve());
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("\r\n\r\nhello"));
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hot_restart_hands_over_listeners() {
+        use std::os::fd::AsRawFd;
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let https = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(
+            "echo $LISTEN_FDS $LISTEN_FDNAMES; \
+             test -S /proc/self/fd/$LISTEN_FDS_FIRST_FD && \
+             test -S /proc/self/fd/$((LISTEN_FDS_FIRST_FD + 1)) && echo inherited",
+        );
+        command.stdout(std::process::Stdio::piped());
+        let child = crate::restart::spawn_with_listeners(
+            command,
+            &[(http.as_raw_fd(), "http"), (https.as_raw_fd(), "https")],
+        )
+        .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "2 http:https\ninherited\n"
+        );
+    }
+
+    fn pid_handler(_req: NewRequestType) -> HandlerResponse<'static> {
+        Box::pin(async move { respond((http::StatusCode::OK, std::process::id().to_string())) })
+    }
+
+    // Body of a GET / over tls, the certificate is checked against testdata/ca.pem
+    #[cfg(target_os = "linux")]
+    fn tls_get(port: u16) -> String {
+        use std::io::{Read, Write};
+        let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in crate::tls::load_certificates_from_pem(ca).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = rustls::ClientConnection::new(
+            std::sync::Arc::new(config),
+            "localhost".try_into().unwrap(),
+        )
+        .unwrap();
+        let socket = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let timeout = Some(std::time::Duration::from_secs(5));
+        socket.set_read_timeout(timeout).unwrap();
+        socket.set_write_timeout(timeout).unwrap();
+        let mut tls = rustls::StreamOwned::new(conn, socket);
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut resp = Vec::new();
+        // the connection is closed without a close_notify
+        let _ = tls.read_to_end(&mut resp);
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        resp.split("\r\n\r\n").nth(1).unwrap().to_string()
+    }
+
+    // Runs serve_with_hot_restart in a child process started from this test binary. The child
+    // restarts itself on SIGUSR2, the new process has to keep serving the handed over socket
+    // over tls
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hot_restart_keeps_tls() {
+        let cert = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.pem");
+        if std::env::var("HTTPRS_HOT_RESTART_TEST").is_ok() {
+            let activated = crate::systemd::listeners_from_env().unwrap();
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            return runtime.block_on(async {
+                let router = Router::<AppState>::new()
+                    .add_handler("/", Handler::Without(pid_handler))
+                    .unwrap()
+                    .make_into_serveable();
+                let server = match activated.is_empty() {
+                    true => router.bind_tls("127.0.0.1:0", cert).await.unwrap(),
+                    false => router.bind_activated_tls(activated, cert).unwrap(),
+                };
+                println!("listening on {}", server.local_addr().unwrap().port());
+                // so no process is left behind when the test fails
+                let limit = std::time::Duration::from_secs(30);
+                let _ = tokio::time::timeout(limit, server.serve_with_hot_restart()).await;
+            });
+        }
+        hot_restart(std::env::current_exe().unwrap(), || ());
+    }
+
+    // A deploy renames the new binary over the old one while the server is running, the
+    // restart has to start the binary that is at the path now
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hot_restart_runs_replaced_binary() {
+        let dir = std::env::temp_dir().join(format!("httprs-deploy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("app");
+        std::fs::copy(std::env::current_exe().unwrap(), &exe).unwrap();
+        hot_restart(exe.clone(), || {
+            let new = dir.join("app.new");
+            std::fs::copy(std::env::current_exe().unwrap(), &new).unwrap();
+            std::fs::rename(&new, &exe).unwrap();
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Starts the server of hot_restart_keeps_tls from exe, calls deploy and restarts it
+    #[cfg(target_os = "linux")]
+    fn hot_restart(exe: std::path::PathBuf, deploy: impl FnOnce()) {
+        use std::io::BufRead;
+        let mut command = std::process::Command::new(exe);
+        command
+            .args([
+                "tests::hot_restart_keeps_tls",
+                "--exact",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env("HTTPRS_HOT_RESTART_TEST", "1")
+            .stdout(std::process::Stdio::piped());
+        // a copied binary can still be open for writing in a child another test just forked
+        let mut old = loop {
+            match command.spawn() {
+                Err(e) if e.raw_os_error() == Some(nix::errno::Errno::ETXTBSY as i32) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                spawned => break spawned.unwrap(),
+            }
+        };
+        let mut stdout = std::io::BufReader::new(old.stdout.take().unwrap());
+        let port: u16 = loop {
+            let mut line = String::new();
+            assert_ne!(stdout.read_line(&mut line).unwrap(), 0);
+            if let Some((_, port)) = line.split_once("listening on ") {
+                break port.trim().parse().unwrap();
+            }
+        };
+        assert_eq!(tls_get(port), old.id().to_string());
+        deploy();
+
+        let kill = |signal: &str, pid: &str| {
+            let status = std::process::Command::new("kill")
+                .args([signal, pid])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        kill("-USR2", &old.id().to_string());
+        // the old process only exits once the new one was started
+        assert!(old.wait().unwrap().success());
+        let new = tls_get(port);
+        assert_ne!(new, old.id().to_string());
+        kill("-TERM", &new);
+        // the new process inherited stdout, it is closed once that exited as well
+        let mut rest = String::new();
+        std::io::Read::read_to_string(&mut stdout, &mut rest).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_privileges() {
+        use crate::privileges::DropPrivileges;
+        let (uid, gid) = DropPrivileges::new().user("root").resolve().unwrap();
+        assert!(uid.unwrap().is_root());
+        assert_eq!(gid.unwrap().as_raw(), 0);
+        let err = DropPrivileges::new()
+            .user("no-such-user-httprs")
+            .resolve()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        // nothing configured means nothing changes
+        DropPrivileges::new().apply().unwrap();
+    }
+
+    #[test]
+    fn strict_parsing() {
+        use crate::parse::{parse_request, parse_request_strict};
+        use crate::request::ParseError;
+        let req =
+            parse_request_strict(b"GET /a?b=c HTTP/1.1\r\nHost: x\r\nX-A:  1 \r\nx-a: 2\r\n\r\n")
+                .unwrap();
+        assert_eq!(req.metadata.path, "/a");
+        assert_eq!(req.params.unwrap()["b"], "c");
+        assert_eq!(req.headers["x-a"], "1, 2");
+
+        let rejected: [&[u8]; 10] = [
+            b"GET / HTTP/1\r\n\r\n",
+            b"GET / HTTP/1.1\nHost: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nA: b\r\n c\r\n\r\n",
+            b"GET / HTTP/1.1\r\nA: b\x01\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1, 1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 2\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+        ];
+        for head in rejected {
+            assert!(
+                matches!(parse_request_strict(head), Err(ParseError::Invalid(_))),
+                "{}",
+                String::from_utf8_lossy(head)
+            );
+        }
+        assert!(matches!(
+            parse_request_strict(b"POST / HTTP/1.1\r\nTransfer-Encoding: foo, chunked\r\n\r\n"),
+            Err(ParseError::UnsupportedTransferEncoding)
+        ));
+        // the lenient parser still takes what legacy clients send
+        let head = std::borrow::Cow::Borrowed("GET / HTTP/1\r\n\r\n");
+        assert_eq!(parse_request(&head).unwrap().metadata.version, "HTTP/1");
+    }
+
+    #[test]
+    fn request_target_forms() {
+        use crate::parse::{effective_uri, parse_request_strict};
+        use crate::request::ParseError;
+        use http::uri::Scheme;
+        let req =
+            parse_request_strict(b"GET http://example.com/a?b=c HTTP/1.1\r\nHost: other\r\n\r\n")
+                .unwrap();
+        assert_eq!(req.metadata.path, "/a");
+        assert_eq!(req.params.as_ref().unwrap()["b"], "c");
+        // the authority of the target wins over Host
+        assert_eq!(
+            effective_uri(&req, Scheme::HTTP).to_string(),
+            "http://example.com/a?b=c"
+        );
+
+        let req = parse_request_strict(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.metadata.path, "");
+        assert_eq!(req.uri.authority().unwrap(), "example.com:443");
+
+        let req = parse_request_strict(b"OPTIONS * HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.metadata.path, "*");
+
+        let req =
+            parse_request_strict(b"GET /a?b=c HTTP/1.1\r\nHost: example.com:8443\r\n\r\n").unwrap();
+        assert_eq!(
+            effective_uri(&req, Scheme::HTTPS).to_string(),
+            "https://example.com:8443/a?b=c"
+        );
+
+        for head in [
+            &b"GET example.com:443 HTTP/1.1\r\n\r\n"[..],
+            b"CONNECT /a HTTP/1.1\r\n\r\n",
+            b"GET * HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(matches!(
+                parse_request_strict(head),
+                Err(ParseError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn path_normalization() {
+        use crate::parse::{normalize_path, parse_params_from_path};
+        let cases = [
+            ("/a/./b/../c", false, "/a/c"),
+            ("/a/b/..", false, "/a/"),
+            ("/../../a", false, "/a"),
+            ("/a/%2e%2E/b", false, "/b"),
+            ("/user/j%C3%B6rg", false, "/user/jörg"),
+            ("/a%2fb/%25", false, "/a%2Fb/%25"),
+            ("//user//1/", false, "//user//1/"),
+            ("//user//1/", true, "/user/1/"),
+            ("/", true, "/"),
+        ];
+        for (path, merge_slashes, expected) in cases {
+            assert_eq!(normalize_path(path, merge_slashes).unwrap(), expected);
+        }
+        for path in ["/a%zz", "/a%2", "/a%00", "/%ff"] {
+            assert!(normalize_path(path, false).is_err(), "{path}");
+        }
+        let params = parse_params_from_path("q=a%20b+c&n%C3%A4me=1").unwrap();
+        assert_eq!(params["q"], "a b c");
+        assert_eq!(params["näme"], "1");
+    }
+
     #[test]
     fn parse() {
         let test_lines = vec![
@@ -50,4 +1658,64 @@ mod tests {
             assert_eq!(parse_res, i.1)
         }
     }
+
+    #[derive(crate::response::IntoResp)]
+    enum ApiError {
+        #[status(404)]
+        #[body = "text"]
+        NotFound,
+        #[status(400)]
+        #[body = "json"]
+        Invalid {
+            field: String,
+        },
+        #[status(409)]
+        #[body = "json"]
+        Conflict(u32),
+        Internal,
+    }
+    impl std::fmt::Display for ApiError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "not found")
+        }
+    }
+    #[derive(serde::Serialize, crate::response::IntoResp)]
+    #[status(201)]
+    #[content_type = "application/json"]
+    struct Created {
+        id: u32,
+    }
+    #[derive(crate::response::IntoResp)]
+    #[content_type = "text/html"]
+    struct Page(String);
+    impl std::fmt::Display for Page {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "<h1>{}</h1>", self.0)
+        }
+    }
+    #[test]
+    fn derive_into_resp() {
+        use crate::response::IntoResp;
+        let text = |resp: &dyn IntoResp| String::from_utf8(resp.into_response()).unwrap();
+        assert_eq!(
+            text(&ApiError::NotFound),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\nContent-type: text/plain; charset=utf-8\r\n\r\nnot found"
+        );
+        assert_eq!(
+            text(&ApiError::Invalid {
+                field: "name".to_string()
+            }),
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 16\r\nContent-type: application/json\r\n\r\n{\"field\":\"name\"}"
+        );
+        assert!(text(&ApiError::Conflict(7)).starts_with("HTTP/1.1 409 Conflict\r\n"));
+        assert!(text(&ApiError::Conflict(7)).ends_with("\r\n\r\n7"));
+        assert!(text(&ApiError::Internal).starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert_eq!(
+            text(&Created { id: 3 }),
+            "HTTP/1.1 201 Created\r\nContent-Length: 8\r\nContent-type: application/json\r\n\r\n{\"id\":3}"
+        );
+        assert!(
+            text(&Page("hi".to_string())).ends_with("Content-type: text/html\r\n\r\n<h1>hi</h1>")
+        );
+    }
 }
@@ -1,6 +1,11 @@
 #![forbid(unsafe_code)]
+use crate::conn::PendingBody;
+use crate::server::PeerCredentials;
 use crate::types::Method;
 use bytes::BytesMut;
+use http::uri::Authority;
+use http::uri::Scheme;
+use http::Uri;
 use serde::de::DeserializeOwned;
 
 use crate::request::Body;
@@ -118,8 +123,29 @@ pub struct NewRequestType {
     pub body: Option<BytesMut>,
     pub headers: HashMap<String, String>,
     pub params: Option<HashMap<String, String>>,
+    // The effective request uri (RFC 9112 3.3). The target as it was sent for absolute-form,
+    // otherwise rebuilt from the scheme of the connection, the Host header and the target.
+    // Only the path and query are set if there is no Host
+    pub uri: Uri,
+    // Only set for connections over a unix socket
+    pub peer_credentials: Option<PeerCredentials>,
+    // Set instead of body if the client waits for a 100 Continue, see read_body
+    pub pending_body: Option<PendingBody>,
 }
 impl NewRequestType {
+    // The body, read from the connection first if the client waits for a 100 Continue before
+    // sending it (see conn::PendingBody). body is only filled in once this was called for those
+    pub async fn read_body(&mut self) -> std::io::Result<Option<&BytesMut>> {
+        if let Some(pending) = self.pending_body.take() {
+            self.body = Some(pending.read().await?);
+        }
+        Ok(self.body.as_ref())
+    }
+    // Same as from_json_to_struct, the body is read first if it was not read yet
+    pub async fn read_json_to_struct<T: DeserializeOwned>(&mut self) -> std::io::Result<T> {
+        self.read_body().await?;
+        self.from_json_to_struct()
+    }
     pub fn from_json_to_struct<T: DeserializeOwned>(&self) -> std::io::Result<T> {
         match &self.body {
             None => return Err(std::io::Error::from(std::io::ErrorKind::InvalidData)),
@@ -215,7 +241,7 @@ pub fn parse_params_from_path(path_after_question_mark: &str) -> Option<HashMap<
                 match key {
                     Some(key) => match val {
                         Some(val) => {
-                            map.insert(key.to_string(), val.to_string());
+                            map.insert(decode_query(key), decode_query(val));
                         }
                         None => continue 'outer,
                     },
@@ -241,7 +267,7 @@ pub fn parse_params_from_path(path_after_question_mark: &str) -> Option<HashMap<
                     Some(key) => match val {
                         Some(val) => {
                             let mut map_local = HashMap::new();
-                            map_local.insert(key.to_string(), val.to_string());
+                            map_local.insert(decode_query(key), decode_query(val));
                             map = Some(map_local)
                         }
                         None => continue 'outer,
@@ -259,21 +285,28 @@ pub fn parse_request(req_str: &Cow<'_, str>) -> Result<NewRequestType, ParseErro
     let mut header_before = false;
     let mut count = 0;
     let req_string = req_str.clone();
-    let mut lines: Vec<&str> = req_string.split("\r\n").collect();
+    // legacy clients end lines with a bare LF
+    let mut lines: Vec<&str> = req_string
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
     for line in &lines {
         count += 1;
         if count == 1 {
             match parse_new_method_line(&line) {
                 Some(mut parse_res) => {
-                    if parse_res.path.contains("?") {
-                        let split_res = parse_res.path.split_once("?").unwrap();
-                        match parse_params_from_path(split_res.1) {
-                            Some(params) => request.params = Some(params),
-                            None => (),
-                        };
-                        parse_res.path = split_res.0.to_string();
-                    }
+                    let target = std::mem::take(&mut parse_res.path);
                     request.metadata = parse_res;
+                    // targets that are not a valid uri are routed as they are
+                    if set_target(&mut request, &target).is_err() {
+                        match target.split_once("?") {
+                            Some((path, query)) => {
+                                request.params = parse_params_from_path(query);
+                                request.metadata.path = path.to_string();
+                            }
+                            None => request.metadata.path = target,
+                        }
+                    }
                 }
 
                 None => return Err(ParseError::NotValidRequest),
@@ -281,9 +314,10 @@ pub fn parse_request(req_str: &Cow<'_, str>) -> Result<NewRequestType, ParseErro
             continue;
         }
         if let Some(header) = parse_header_new(line) {
+            // header names are case insensitive so they are stored lowercase
             request
                 .headers
-                .insert(header.0.to_string(), header.0.to_string());
+                .insert(header.0.to_lowercase(), header.1.to_string());
             header_before = true;
             continue;
         }
@@ -306,3 +340,321 @@ pub fn parse_request(req_str: &Cow<'_, str>) -> Result<NewRequestType, ParseErro
 
     return Ok(request.clone());
 }
+
+// Parses a request head (request line and headers up to and including the empty line)
+// following RFC 9112. Everything a proxy could interpret differently is rejected:
+// bare CR or LF, obs-fold, whitespace before the colon, bytes that are not allowed in names
+// or values, versions other than HTTP/x.y, more than one Host or Content-Length, and
+// Transfer-Encoding together with Content-Length or with a chain that is not understood
+pub fn parse_request_strict(head: &[u8]) -> Result<NewRequestType, ParseError> {
+    if let Some(pos) = find_bare_line_break(head) {
+        return Err(ParseError::Invalid(match head[pos] {
+            b'\n' => "bare LF",
+            _ => "bare CR",
+        }));
+    }
+    let head = match head.strip_suffix(b"\r\n\r\n") {
+        Some(head) => head,
+        None => return Err(ParseError::Invalid("head does not end with an empty line")),
+    };
+    // every line ends with CRLF now, the last one lost it with the suffix
+    let mut lines = head
+        .split(|byte| *byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let request_line = match lines.next() {
+        Some(line) => line,
+        None => return Err(ParseError::Empty),
+    };
+    let mut request = NewRequestType {
+        metadata: parse_request_line_strict(request_line)?,
+        ..Default::default()
+    };
+    let target = std::mem::take(&mut request.metadata.path);
+    set_target(&mut request, &target)?;
+    for line in lines {
+        let (name, val) = parse_header_strict(line)?;
+        match request.headers.get_mut(&name) {
+            None => {
+                request.headers.insert(name, val);
+            }
+            Some(_) if name == "host" || name == "content-length" => {
+                return Err(ParseError::Invalid("duplicate Host or Content-Length"));
+            }
+            // repeated fields are the same as one field with a comma separated list
+            Some(existing) => {
+                existing.push_str(", ");
+                existing.push_str(&val);
+            }
+        }
+    }
+    if let Some(len) = request.headers.get("content-length") {
+        if len.is_empty() || !len.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ParseError::Invalid("Content-Length is not a number"));
+        }
+    }
+    if let Some(codings) = request.headers.get("transfer-encoding") {
+        if request.headers.contains_key("content-length") {
+            return Err(ParseError::Invalid("Transfer-Encoding with Content-Length"));
+        }
+        check_transfer_codings(codings)?;
+    }
+    Ok(request)
+}
+// Position of a CR or LF that is not part of a CRLF
+pub fn find_bare_line_break(buf: &[u8]) -> Option<usize> {
+    buf.iter().enumerate().position(|(idx, byte)| match byte {
+        b'\n' => idx == 0 || buf[idx - 1] != b'\r',
+        // a CR at the very end could still be followed by a LF that was not read yet
+        b'\r' => matches!(buf.get(idx + 1), Some(next) if *next != b'\n'),
+        _ => false,
+    })
+}
+fn parse_request_line_strict(line: &[u8]) -> Result<NewMetaData, ParseError> {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return Err(ParseError::Invalid("request line is not ascii")),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::CannotParseMetaData),
+    };
+    let method = match Method::from_bytes(method.as_bytes()) {
+        Ok(method) => method,
+        Err(_) => return Err(ParseError::CannotParseMetaData),
+    };
+    if target.is_empty() || !target.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(ParseError::Invalid("invalid request target"));
+    }
+    if !is_valid_version(version) {
+        return Err(ParseError::Invalid("invalid version"));
+    }
+    Ok(NewMetaData {
+        method,
+        path: target.to_string(),
+        version: version.to_string(),
+    })
+}
+// HTTP-version = "HTTP/" DIGIT "." DIGIT
+pub fn is_valid_version(version: &str) -> bool {
+    match version.as_bytes() {
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] => {
+            major.is_ascii_digit() && minor.is_ascii_digit()
+        }
+        _ => false,
+    }
+}
+// Returns the lowercase name and the value without the surrounding whitespace
+fn parse_header_strict(line: &[u8]) -> Result<(String, String), ParseError> {
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return Err(ParseError::Invalid("obsolete line folding"));
+    }
+    let colon = match line.iter().position(|byte| *byte == b':') {
+        Some(colon) => colon,
+        None => return Err(ParseError::Invalid("header without colon")),
+    };
+    let (name, val) = (&line[..colon], &line[colon + 1..]);
+    // this also rejects whitespace between the name and the colon
+    if name.is_empty() || !name.iter().all(|byte| is_tchar(*byte)) {
+        return Err(ParseError::Invalid("invalid header name"));
+    }
+    let val = val.trim_ascii();
+    if !val.iter().all(|byte| is_field_vchar(*byte)) {
+        return Err(ParseError::Invalid("invalid header value"));
+    }
+    Ok((
+        String::from_utf8_lossy(name).to_lowercase(),
+        String::from_utf8_lossy(val).to_string(),
+    ))
+}
+// token characters, RFC 9110 5.6.2
+fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+// VCHAR, obs-text and the whitespace allowed inside of a value
+fn is_field_vchar(byte: u8) -> bool {
+    matches!(byte, b'\t' | b' ' | 0x21..=0x7e | 0x80..=0xff)
+}
+// chunked has to be the last coding, otherwise the end of the body is unknown
+fn check_transfer_codings(codings: &str) -> Result<(), ParseError> {
+    let codings: Vec<String> = codings
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .collect();
+    for (idx, coding) in codings.iter().enumerate() {
+        match coding.as_str() {
+            "chunked" if idx + 1 == codings.len() => (),
+            "chunked" => return Err(ParseError::Invalid("chunked is not the last coding")),
+            "gzip" | "deflate" | "compress" | "x-gzip" | "x-compress" => (),
+            _ => return Err(ParseError::UnsupportedTransferEncoding),
+        }
+    }
+    if codings.last().map(|coding| coding.as_str()) != Some("chunked") {
+        return Err(ParseError::Invalid("chunked is not the last coding"));
+    }
+    Ok(())
+}
+
+// Parses the request target (RFC 9112 3.2) into request.uri, the path that is routed on and
+// the query params. The path is empty for authority-form since CONNECT is not routed on a path
+pub fn set_target(request: &mut NewRequestType, target: &str) -> Result<(), ParseError> {
+    let uri: Uri = match target.parse() {
+        Ok(uri) => uri,
+        Err(_) => return Err(ParseError::Invalid("invalid request target")),
+    };
+    let is_connect = request.metadata.method == Method::CONNECT;
+    let path = match (uri.scheme(), uri.authority()) {
+        // absolute-form, mostly sent to proxies but servers have to accept it as well
+        (Some(_), Some(_)) if !is_connect => uri.path().to_string(),
+        // authority-form, only used with CONNECT
+        (None, Some(_)) if is_connect && uri.path().is_empty() => String::new(),
+        // asterisk-form, only used with OPTIONS
+        (None, None) if target == "*" && request.metadata.method == Method::OPTIONS => {
+            target.to_string()
+        }
+        // origin-form
+        (None, None) if target.starts_with('/') && !is_connect => uri.path().to_string(),
+        _ => {
+            return Err(ParseError::Invalid(
+                "request target does not fit the method",
+            ))
+        }
+    };
+    request.params = uri.query().and_then(parse_params_from_path);
+    request.metadata.path = path;
+    request.uri = uri;
+    Ok(())
+}
+// Rebuilds the effective request uri from the Host header if the target did not carry the
+// authority itself
+pub fn effective_uri(request: &NewRequestType, scheme: Scheme) -> Uri {
+    if request.uri.authority().is_some() {
+        return request.uri.clone();
+    }
+    let authority = match request
+        .headers
+        .get("host")
+        .map(|host| host.parse::<Authority>())
+    {
+        Some(Ok(authority)) => authority,
+        _ => return request.uri.clone(),
+    };
+    let mut parts = http::uri::Parts::default();
+    parts.scheme = Some(scheme);
+    parts.authority = Some(authority);
+    parts.path_and_query = request.uri.path_and_query().cloned();
+    if parts.path_and_query.is_none() || request.metadata.path == "*" {
+        parts.path_and_query = Some(http::uri::PathAndQuery::from_static("/"));
+    }
+    Uri::from_parts(parts).unwrap_or_else(|_| request.uri.clone())
+}
+
+// Percent-decodes a path and removes dot segments (RFC 3986 5.2.4), so /a/./b/../c and
+// /a/%2E%2E/c are routed like /a/c. %2F and %25 stay encoded, an encoded slash would turn
+// into a separator otherwise. The values extracted from the path are decoded once they are
+// matched, see percent_decode. merge_slashes turns //user//1 into /user/1
+pub fn normalize_path(path: &str, merge_slashes: bool) -> Result<String, ParseError> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let byte = match hex {
+            [Some(high), Some(low)] => match (hex_value(high), hex_value(low)) {
+                (Some(high), Some(low)) => high << 4 | low,
+                _ => return Err(ParseError::Invalid("invalid percent-encoding")),
+            },
+            _ => return Err(ParseError::Invalid("invalid percent-encoding")),
+        };
+        match byte {
+            b'/' => decoded.extend_from_slice(b"%2F"),
+            b'%' => decoded.extend_from_slice(b"%25"),
+            byte if byte.is_ascii_control() => {
+                return Err(ParseError::Invalid("control character in path"))
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    let decoded = match String::from_utf8(decoded) {
+        Ok(decoded) => decoded,
+        Err(_) => return Err(ParseError::Invalid("path is not utf-8")),
+    };
+    let parts: Vec<&str> = decoded.trim_start_matches('/').split('/').collect();
+    let mut segments: Vec<&str> = Vec::with_capacity(parts.len());
+    for (idx, segment) in parts.iter().enumerate() {
+        let last = idx + 1 == parts.len();
+        match *segment {
+            "." | ".." => {
+                if *segment == ".." {
+                    segments.pop();
+                }
+                // /a/b/.. is /a/ and not /a
+                if last {
+                    segments.push("");
+                }
+            }
+            "" if merge_slashes && !last => (),
+            segment => segments.push(segment),
+        }
+    }
+    // the leading slashes were trimmed above so they are merged as well
+    let leading = match merge_slashes {
+        true => 1,
+        false => std::cmp::max(1, decoded.len() - decoded.trim_start_matches('/').len()),
+    };
+    Ok("/".repeat(leading) + &segments.join("/"))
+}
+// Decodes every percent-encoded byte, sequences that are invalid are kept as they are
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let hex = (bytes.get(idx + 1).copied(), bytes.get(idx + 2).copied());
+        match (bytes[idx], hex) {
+            (b'%', (Some(high), Some(low))) => match (hex_value(high), hex_value(low)) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    idx += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            (byte, _) => decoded.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+// Query strings are usually form encoded where + stands for a space
+fn decode_query(input: &str) -> String {
+    percent_decode(&input.replace('+', " "))
+}
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+// Encodes everything in a normalized path that is not allowed in a uri path, the %2F and %25
+// left by normalize_path are kept
+pub fn percent_encode_path(path: &str) -> String {
+    percent_encode(path, |byte| byte == b'/' || byte == b'%')
+}
+// Encodes a value for a single path segment, / and % included so it is extracted unchanged
+pub fn percent_encode_segment(segment: &str) -> String {
+    percent_encode(segment, |_| false)
+}
+fn percent_encode(input: &str, keep: impl Fn(u8) -> bool) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' => encoded.push(byte as char),
+            byte if keep(byte) => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
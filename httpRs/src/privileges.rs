@@ -0,0 +1,83 @@
+#![forbid(unsafe_code)]
+use nix::unistd::Gid;
+use nix::unistd::Group;
+use nix::unistd::Uid;
+use nix::unistd::User;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+// What to give up after the listeners are bound, so ports like 80/443 can be bound as root
+// without serving requests as root. Applied with Server::drop_privileges before accepting.
+#[derive(Clone, Debug, Default)]
+pub struct DropPrivileges {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+}
+impl DropPrivileges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+    // Defaults to the primary group of user if not set
+    pub fn group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+    // Usually the directory static files are served from
+    pub fn chroot(mut self, path: impl Into<PathBuf>) -> Self {
+        self.chroot = Some(path.into());
+        self
+    }
+    // Looks up the uid and gid, this has to happen before the chroot since /etc/passwd
+    // and /etc/group are usually not reachable from inside of it
+    pub fn resolve(&self) -> std::io::Result<(Option<Uid>, Option<Gid>)> {
+        let user = match &self.user {
+            Some(name) => Some(
+                User::from_name(name)?
+                    .ok_or_else(|| not_found(format!("user {name} does not exist")))?,
+            ),
+            None => None,
+        };
+        let gid = match &self.group {
+            Some(name) => Some(
+                Group::from_name(name)?
+                    .ok_or_else(|| not_found(format!("group {name} does not exist")))?
+                    .gid,
+            ),
+            None => user.as_ref().map(|user| user.gid),
+        };
+        Ok((user.map(|user| user.uid), gid))
+    }
+    // The order matters: chroot needs root, and the groups have to be changed before the user
+    // since a non root user can not change them anymore
+    pub fn apply(&self) -> std::io::Result<()> {
+        let (uid, gid) = self.resolve()?;
+        if let Some(path) = &self.chroot {
+            nix::unistd::chroot(path)?;
+            nix::unistd::chdir("/")?;
+        }
+        if let Some(gid) = gid {
+            nix::unistd::setgroups(&[gid])?;
+            nix::unistd::setgid(gid)?;
+        }
+        if let Some(uid) = uid {
+            nix::unistd::setuid(uid)?;
+            // make sure root can not be regained
+            if !uid.is_root() && nix::unistd::setuid(Uid::from_raw(0)).is_ok() {
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "privileges could not be dropped",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn not_found(msg: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, msg)
+}
@@ -66,6 +66,10 @@ pub enum ParseError {
     Empty,
     NotValidRequest,
     CannotParseMetaData,
+    // Rejected by the strict parser, the reason is only meant for logging
+    Invalid(&'static str),
+    // A transfer coding the server does not understand, answered with 501
+    UnsupportedTransferEncoding,
 }
 #[derive(Debug, Clone)]
 pub struct Request {
@@ -6,6 +6,7 @@ use std::collections::HashMap;
 use http::StatusCode;
 
 use crate::router::{self, Cookie, Json};
+pub use httpRs_macros::IntoResp;
 
 pub trait IntoResp {
     fn into_response(&self) -> Vec<u8>;
@@ -115,7 +116,7 @@ impl IntoResp for StatusCode {
 impl IntoResp for (StatusCode, Vec<u8>) {
     fn into_response(&self) -> Vec<u8> {
         let response = format!(
-            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n\r\n",
             self.0.as_u16(),
             self.0.into_status_message(),
             self.1.len(),
@@ -179,6 +180,18 @@ impl IntoResp for (StatusCode, Cookie, String) {
         Vec::from(response)
     }
 }
+// Response with a body of any content type, used by #[derive(IntoResp)]
+pub fn response_with_body(status: StatusCode, content_type: &str, body: &str) -> Vec<u8> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-type: {}\r\n\r\n{}",
+        status.as_u16(),
+        status.into_status_message(),
+        body.len(),
+        content_type,
+        body
+    );
+    Vec::from(response)
+}
 trait IntoMessage {
     fn into_status_message(&self) -> String;
 }
@@ -0,0 +1,81 @@
+#![forbid(unsafe_code)]
+use nix::fcntl::fcntl;
+use nix::fcntl::FcntlArg;
+use std::ffi::OsStr;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+
+// Lowest fd number we hand the sockets over at, like systemd does
+const FIRST_INHERITED_FD: RawFd = 3;
+
+// Command that starts the current binary again with the same arguments.
+// During a deploy the binary on disk was replaced so this runs the new version
+pub fn current_exe_command() -> std::io::Result<Command> {
+    let mut command = Command::new(current_exe()?);
+    command.args(std::env::args_os().skip(1));
+    Ok(command)
+}
+
+// Path the current binary was started from. A deploy that renames the new binary over the
+// old one leaves /proc/self/exe pointing at "<path> (deleted)", the new binary is at <path>
+fn current_exe() -> std::io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    match exe.as_os_str().as_bytes().strip_suffix(b" (deleted)") {
+        Some(path) if !exe.exists() => Ok(PathBuf::from(OsStr::from_bytes(path))),
+        _ => Ok(exe),
+    }
+}
+
+// Starts command with the listening sockets fds inherited, using the LISTEN_FDS convention
+// so the new process can pick them up with systemd::listeners_from_env.
+// LISTEN_PID is left unset since the pid of the child is not known before it is spawned,
+// the receiving side accepts that.
+pub fn spawn_with_listeners(mut command: Command, fds: &[(RawFd, &str)]) -> std::io::Result<Child> {
+    let first_fd = dup_contiguous(fds.iter().map(|(fd, _)| *fd).collect())?;
+    let names: Vec<&str> = fds.iter().map(|(_, name)| *name).collect();
+    command
+        .env("LISTEN_FDS", fds.len().to_string())
+        .env("LISTEN_FDS_FIRST_FD", first_fd.to_string())
+        .env("LISTEN_FDNAMES", names.join(":"))
+        .env_remove("LISTEN_PID");
+    let child = command.spawn();
+    // The child has its own copies now
+    for offset in 0..fds.len() {
+        let _ = nix::unistd::close(first_fd + offset as RawFd);
+    }
+    child
+}
+
+// Duplicates fds to a block of consecutive fd numbers without FD_CLOEXEC so they survive
+// the exec. F_DUPFD takes the lowest free number from the one given, if another fd is
+// already in the way we start over behind it. Returns the first fd of the block
+fn dup_contiguous(fds: Vec<RawFd>) -> std::io::Result<RawFd> {
+    let mut first = FIRST_INHERITED_FD;
+    'outer: loop {
+        let mut duped: Vec<RawFd> = Vec::with_capacity(fds.len());
+        for (offset, fd) in fds.iter().enumerate() {
+            let wanted = first + offset as RawFd;
+            let new_fd = match fcntl(*fd, FcntlArg::F_DUPFD(wanted)) {
+                Ok(new_fd) => new_fd,
+                Err(e) => {
+                    for fd in duped {
+                        let _ = nix::unistd::close(fd);
+                    }
+                    return Err(e.into());
+                }
+            };
+            if new_fd != wanted {
+                for fd in duped.into_iter().chain(std::iter::once(new_fd)) {
+                    let _ = nix::unistd::close(fd);
+                }
+                first = new_fd;
+                continue 'outer;
+            }
+            duped.push(new_fd);
+        }
+        return Ok(first);
+    }
+}
@@ -1,22 +1,42 @@
 #![forbid(unsafe_code)]
+use crate::config::exceeds;
+use crate::config::ParseMode;
+use crate::config::RouteConfig;
+use crate::config::ServerConfig;
+use crate::config::TrailingSlash;
+use crate::conn;
+use crate::conn::Read;
 use crate::parse;
 use crate::parse::NewRequestType;
 use crate::request::parse_request;
+use crate::request::ParseError;
 use crate::request::ToRequest;
+use crate::response::respond;
+use crate::server::serve_listener;
+use crate::server::Connection;
+use crate::server::Listener;
+use crate::server::Server;
+use crate::server::ServerHandle;
+use crate::server::Stream;
+use crate::state::FromRef;
+use crate::state::State;
+use crate::types::Method;
 use crate::{request::Request, response::IntoResp};
-use async_std::sync::Arc;
+use arc_swap::ArcSwap;
 use bytes::Bytes;
+use bytes::BytesMut;
 use http::StatusCode;
+use http::Version;
 use std::pin::Pin;
-use std::vec;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
 use std::{collections::HashMap, future::Future};
-use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
+use tokio::time::Instant;
 use tokio_rustls::TlsAcceptor;
 // This list isn't exhaustive will add to it in the future
-const LIST_UNSUPPORTED: &[char] = &['.', '&', '(', ')', '=', '}', '{', '$'];
+const LIST_UNSUPPORTED: &[char] = &['&', '(', ')', '=', '}', '{', '$'];
 
 // Middleware definitions
 /*
@@ -31,26 +51,110 @@ pub type MiddleWareFunctionType<T> =
 pub type HandlerResponse<'a> = Pin<Box<dyn Future<Output = Box<dyn IntoResp + Send>> + Send + 'a>>;
 pub type HandlerType = fn(NewRequestType) -> HandlerResponse<'static>;
 
-pub type HandlerTypeState<T> = fn(NewRequestType, T) -> HandlerResponse<'static>;
+pub type HandlerTypeState<T> = fn(NewRequestType, State<T>) -> HandlerResponse<'static>;
 pub type HandlerTypeStateAndExtract<T> =
-    fn(NewRequestType, T, HashMap<String, String>) -> HandlerResponse<'static>;
+    fn(NewRequestType, State<T>, HashMap<String, String>) -> HandlerResponse<'static>;
+// Handler that was written for another state type, it receives the state of the router it is
+// registered on and derives its own state from it (see Handler::from_substate and Router::nest)
+pub type HandlerTypeErased<T> = Arc<
+    dyn Fn(
+            NewRequestType,
+            Option<State<T>>,
+            Option<HashMap<String, String>>,
+        ) -> HandlerResponse<'static>
+        + Send
+        + Sync,
+>;
+// Registration function generated by the route attributes, see httpRs_macros and Router::mount
+pub type RouteFn<T> = fn(Router<T>) -> std::result::Result<Router<T>, RouteError>;
 
-#[derive(Debug, Default, Clone)]
-pub enum Handler<T: std::clone::Clone> {
+#[derive(Default)]
+pub enum Handler<T> {
     #[default]
     None,
     Without(HandlerType),
     WithState(HandlerTypeState<T>),
     WithStateAndExtract(HandlerTypeStateAndExtract<T>),
+    Erased(HandlerTypeErased<T>),
 }
-impl<T: std::clone::Clone> Handler<T>
+// Implemented by hand since deriving would require T: Clone
+// even though only function pointers are stored
+impl<T> Clone for Handler<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Handler::None => Handler::None,
+            Handler::Without(func) => Handler::Without(*func),
+            Handler::WithState(func) => Handler::WithState(*func),
+            Handler::WithStateAndExtract(func) => Handler::WithStateAndExtract(*func),
+            Handler::Erased(func) => Handler::Erased(Arc::clone(func)),
+        }
+    }
+}
+impl<T> std::fmt::Debug for Handler<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Handler::None => f.write_str("None"),
+            Handler::Without(func) => f.debug_tuple("Without").field(func).finish(),
+            Handler::WithState(func) => f.debug_tuple("WithState").field(func).finish(),
+            Handler::WithStateAndExtract(func) => {
+                f.debug_tuple("WithStateAndExtract").field(func).finish()
+            }
+            Handler::Erased(_) => f.write_str("Erased"),
+        }
+    }
+}
+impl<T> Handler<T>
 where
-    T: Clone,
+    T: Send,
+    T: Sync,
+    T: 'static,
 {
+    // Turns a handler that uses a part of the state into a handler for the whole state.
+    // For example a Handler<DbPool> can be registered on a Router<AppState> if DbPool: FromRef<AppState>
+    pub fn from_substate<S>(handler: Handler<S>) -> Self
+    where
+        S: FromRef<T> + Send + Sync + 'static,
+    {
+        Handler::derive_state(handler, |state: &Option<State<T>>| {
+            state.as_ref().map(|state| state.substate())
+        })
+    }
+    fn derive_state<S, F>(handler: Handler<S>, derive: F) -> Self
+    where
+        S: Send + Sync + 'static,
+        F: Fn(&Option<State<T>>) -> Option<State<S>> + Send + Sync + 'static,
+    {
+        match handler {
+            Handler::None => Handler::None,
+            Handler::Without(func) => Handler::Without(func),
+            handler => Handler::Erased(Arc::new(move |req, state, extracts| {
+                let handler = handler.clone();
+                let state = derive(&state);
+                Box::pin(async move {
+                    match handler.handle(req, state, extracts).await {
+                        Some(res) => res,
+                        None => respond(StatusCode::NOT_FOUND),
+                    }
+                })
+            })),
+        }
+    }
+}
+impl<T> Handler<T> {
+    // Name of the variant, e.g. for Router::routes
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Handler::None => "None",
+            Handler::Without(_) => "Without",
+            Handler::WithState(_) => "WithState",
+            Handler::WithStateAndExtract(_) => "WithStateAndExtract",
+            Handler::Erased(_) => "Erased",
+        }
+    }
     pub async fn handle(
         self,
         req: NewRequestType,
-        state: Option<T>,
+        state: Option<State<T>>,
         extracts: Option<HashMap<String, String>>,
     ) -> Option<Box<dyn IntoResp + Send>> {
         match self {
@@ -84,6 +188,7 @@ where
                 Some(func(req, state, extract).await)
             }
 
+            Self::Erased(func) => Some(func(req, state, extracts).await),
             Self::None => None,
         }
     }
@@ -185,104 +290,201 @@ impl Cookie {
     }
 }
 
-pub struct RoutingResult<T: std::clone::Clone> {
+pub struct RoutingResult<T> {
     pub handler: Handler<T>,
     pub extract: Option<HashMap<String, String>>,
+    // Set if the route was registered with Router::add_handler_with_config
+    pub config: Option<RouteConfig>,
+    // The path the route was registered with, e.g. /user/:id
+    pub route: String,
+}
+// A registered route as listed by Router::routes
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct RouteInfo {
+    pub path: String,
+    // Set for the routes of a virtual host, see Router::host
+    pub host: Option<String>,
+    // Set for routes registered with Router::add_named
+    pub name: Option<String>,
+    // The method of a route registered with Router::add_method_handler, * if the handler gets
+    // every request for the path
+    pub method: String,
+    // The Handler variant, handlers of nested routers are Erased
+    pub handler: String,
+    // Always empty until there is middleware
+    pub middleware: Vec<String>,
+}
+pub enum Routed<T> {
+    Found(RoutingResult<T>),
+    // Only the path with or without the trailing slash exists, redirect to it
+    Redirect(String),
+    NotFound,
 }
-#[derive(Debug, Default)]
-pub struct Router<T: Clone + Default + Send + std::marker::Sync> {
+// The routes of one host, see Router::host
+#[derive(Debug)]
+pub struct VirtualHost<T> {
+    // Lowercase host without a port, may start with *. to match any subdomain
+    pub pattern: String,
     pub routes: Node<T>,
     pub fallback: Option<Handler<T>>,
 }
+impl<T> VirtualHost<T> {
+    // None if host does not match, otherwise what the pattern captured from it
+    pub fn matches(&self, host: &str) -> Option<HashMap<String, String>> {
+        match self.pattern.strip_prefix('*') {
+            Some(suffix) => match host.strip_suffix(suffix) {
+                Some(subdomain) if !subdomain.is_empty() => Some(HashMap::from([(
+                    "subdomain".to_string(),
+                    subdomain.to_string(),
+                )])),
+                _ => None,
+            },
+            None if host == self.pattern => Some(HashMap::new()),
+            None => None,
+        }
+    }
+}
+// The most specific host that matches, host is compared without its port and case
+pub fn find_host<'a, T>(
+    hosts: &'a [VirtualHost<T>],
+    host: &str,
+) -> Option<(&'a VirtualHost<T>, HashMap<String, String>)> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    hosts
+        .iter()
+        .find_map(|vhost| vhost.matches(&host).map(|captured| (vhost, captured)))
+}
+// Names of routes registered with Router::add_named and their paths, e.g. user_show -> /user/:id.
+// Can be cloned into the state so handlers can build urls as well
+#[derive(Clone, Debug, Default)]
+pub struct RouteNames {
+    pub paths: HashMap<String, String>,
+    // Names of the routes of each virtual host by its pattern, see Router::host.
+    // Every host has its own names so the same name can be used on several hosts
+    pub hosts: HashMap<String, HashMap<String, String>>,
+}
+#[derive(Debug, PartialEq)]
+pub enum UrlError {
+    UnknownRoute(String),
+    MissingParam(String),
+    // A param that is not part of the path
+    ExtraParam(String),
+    // Empty, . or .. would not end up in the param once the url is requested
+    InvalidValue(String),
+}
+// Why a route could not be registered
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    // The path contains unsupported characters or could not be added to the routing tree
+    InvalidPath(String),
+    // The name is already used by another route, see Router::add_named
+    DuplicateName(String),
+    // The method is already registered for the path, the path has a handler for every method
+    // (see Router::add_method_handler) or a nested router has a route at the same path
+    DuplicateRoute(String),
+    // The host pattern is empty, has a port or a * that is not a whole first label, see
+    // Router::host
+    InvalidHost(String),
+    // The router given to Router::host has hosts of its own, they could never match
+    NestedHost(String),
+}
+impl RouteNames {
+    // Fills in the params of the route, the values are percent-encoded so they are extracted
+    // unchanged. Every param of the path has to be given and nothing else
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        match self.paths.get(name) {
+            Some(path) => fill_params(path, params),
+            None => Err(UrlError::UnknownRoute(name.to_string())),
+        }
+    }
+    // Same as url_for for a route of the host registered with Router::host, e.g.
+    // url_for_host("*.tenant.example.com", "tenant_home", &[]). Only the path is built
+    pub fn url_for_host(
+        &self,
+        host: &str,
+        name: &str,
+        params: &[(&str, &str)],
+    ) -> Result<String, UrlError> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self.hosts.get(&host).and_then(|paths| paths.get(name)) {
+            Some(path) => fill_params(path, params),
+            None => Err(UrlError::UnknownRoute(name.to_string())),
+        }
+    }
+    // Adds the names of a nested router, their paths are prefixed like its routes.
+    // Fails if one of them is already used
+    fn nest(&mut self, prefix: &str, names: RouteNames) -> Result<(), RouteError> {
+        let prefix = prefix.trim_end_matches('/');
+        for (name, path) in names.paths {
+            if self.paths.contains_key(&name) {
+                return Err(RouteError::DuplicateName(name));
+            }
+            let full_path = match path.as_str() {
+                "/" if prefix.is_empty() => "/".to_string(),
+                "/" => prefix.to_string(),
+                _ => prefix.to_string() + path.as_str(),
+            };
+            self.paths.insert(name, full_path);
+        }
+        Ok(())
+    }
+}
+fn fill_params(path: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+    let is_param = |key: &str| path.split('/').any(|segment| segment == format!(":{key}"));
+    if let Some((key, _)) = params.iter().find(|(key, _)| !is_param(key)) {
+        return Err(UrlError::ExtraParam(key.to_string()));
+    }
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let param = match segment.strip_prefix(':') {
+            Some(param) => param,
+            None => {
+                segments.push(parse::percent_encode_path(segment));
+                continue;
+            }
+        };
+        match params.iter().find(|(key, _)| *key == param) {
+            None => return Err(UrlError::MissingParam(param.to_string())),
+            Some((_, "" | "." | "..")) => return Err(UrlError::InvalidValue(param.to_string())),
+            Some((_, value)) => segments.push(parse::percent_encode_segment(value)),
+        }
+    }
+    Ok(segments.join("/"))
+}
+#[derive(Debug)]
+pub struct Router<T> {
+    pub routes: Node<T>,
+    pub fallback: Option<Handler<T>>,
+    pub config: ServerConfig,
+    // Routers for other hosts, see Router::host. Exact hosts come first and wildcards are
+    // sorted from the longest to the shortest so the first match is the most specific one
+    pub hosts: Vec<VirtualHost<T>>,
+    pub names: RouteNames,
+}
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Router {
+            routes: Node::default(),
+            fallback: None,
+            config: ServerConfig::default(),
+            hosts: Vec::new(),
+            names: RouteNames::default(),
+        }
+    }
+}
 impl<T> Router<T>
 where
     T: Sync,
-    T: Clone,
-    T: Default,
     T: Send,
-    T: std::fmt::Debug,
+    T: 'static,
 {
     pub fn new() -> Self {
         Router {
             routes: Node::new("/"),
             fallback: None,
-        }
-    }
-    async fn serve(&'static self, addr: &str) -> ! {
-        let listener = match TcpListener::bind(addr).await {
-            Ok(listener) => listener,
-            Err(e) => panic!("Cannot create listener Error: {e} "),
-        };
-        loop {
-            let (socket, _) = match listener.accept().await {
-                Ok((socket, other_thing)) => (socket, other_thing),
-                Err(e) => panic!("Canot accept connection Error: {e}"),
-            };
-
-            tokio::spawn(async move {
-                //                                                Might want to avoid cloning the
-                //                                                state for every connection, maybe
-                //                                                an Arc::clone would be better since it
-                //                                                does not create new memory
-                //
-                match handle_conn_node_based(
-                    socket,
-                    &self.routes,
-                    self.fallback.clone(),
-                    self.routes.state.clone(),
-                )
-                .await
-                {
-                    Ok(_) => (),
-                    Err(e) => {
-                        panic!("Cannot handle incomming connection: {e} \n")
-                    }
-                }
-            });
-        }
-    }
-    async fn serve_tls(&'static self, addr: &str, path_to_cert: &str) -> ! {
-        // need to implement loading the certs
-        // This implementation is very close to the example in the tokio_rustls crate
-        let cert_chain = crate::tls::load_certificates_from_pem(path_to_cert).unwrap();
-        let key_der = crate::tls::load_private_key_from_file(path_to_cert).unwrap();
-        let config = match rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, key_der)
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
-        {
-            Err(e) => panic!("{e}"),
-            Ok(config) => config,
-        };
-        let listener = match TcpListener::bind(addr).await {
-            Ok(listener) => listener,
-            Err(e) => panic!("Cannot create listener Error: {e} "),
-        };
-        let acceptor = TlsAcceptor::from(Arc::new(config));
-        loop {
-            let (socket, _) = match listener.accept().await {
-                Ok((socket, other_thing)) => (socket, other_thing),
-                Err(e) => panic!("Canot accept connection Error: {e}"),
-            };
-            let acceptor = acceptor.clone();
-            tokio::spawn(async move {
-                let stream = match acceptor.accept(socket).await {
-                    Ok(stream) => stream,
-                    Err(e) => panic!("Cannot accept connection {e}"),
-                };
-                match crate::tls::handle_conn_node_based_tls(
-                    tokio_rustls::TlsStream::Server(stream),
-                    &self.routes,
-                    None,
-                    self.routes.state.clone(),
-                )
-                .await
-                {
-                    Ok(()) => (),
-                    Err(e) => panic!("Cannot handle incomming connection: {e}"),
-                };
-            });
+            config: ServerConfig::default(),
+            hosts: Vec::new(),
+            names: RouteNames::default(),
         }
     }
     pub fn add_handler(
@@ -297,57 +499,598 @@ where
         Ok(Router {
             routes: std::mem::take(&mut router),
             fallback: self.fallback.clone(),
+            config: self.config.clone(),
+            hosts: std::mem::take(&mut self.hosts),
+            names: std::mem::take(&mut self.names),
         })
     }
+    // add_handler for the registration methods that return a RouteError, so a path that can
+    // not be added is an InvalidPath
+    fn add_route(
+        &mut self,
+        path: &str,
+        handler: Handler<T>,
+    ) -> std::result::Result<Self, RouteError> {
+        self.add_handler(path, handler)
+            .map_err(|_| RouteError::InvalidPath(path.to_string()))
+    }
+    // Same as add_handler with timeouts that differ from the ones in the ServerConfig
+    pub fn add_handler_with_config(
+        &mut self,
+        path: &str,
+        handler: Handler<T>,
+        config: RouteConfig,
+    ) -> std::result::Result<Self, RouteError> {
+        let mut router = self.add_route(path, handler)?;
+        match router.routes.find_mut(path) {
+            Some(node) => node.config = Some(config),
+            None => return Err(RouteError::InvalidPath(path.to_string())),
+        }
+        Ok(router)
+    }
+    // Same as add_handler, the name can be used with url_for to build urls for the route
+    pub fn add_named(
+        &mut self,
+        name: &str,
+        path: &str,
+        handler: Handler<T>,
+    ) -> std::result::Result<Self, RouteError> {
+        if self.names.paths.contains_key(name) {
+            return Err(RouteError::DuplicateName(name.to_string()));
+        }
+        let mut router = self.add_route(path, handler)?;
+        router
+            .names
+            .paths
+            .insert(name.to_string(), path.to_string());
+        Ok(router)
+    }
+    // Registers handler for one method of path, the route attributes use this. Every method of a
+    // path can have its own handler, requests with any other method get a 405 with the
+    // registered methods in the Allow header
+    pub fn add_method_handler(
+        &mut self,
+        method: Method,
+        path: &str,
+        handler: Handler<T>,
+    ) -> std::result::Result<Self, RouteError> {
+        let (methods, other) = match self.routes.find(path) {
+            Some(node) if node.methods.contains(&method) => {
+                return Err(RouteError::DuplicateRoute(format!("{:?} {}", method, path)))
+            }
+            Some(node) if node.handler.is_some() && node.methods.is_empty() => {
+                return Err(RouteError::DuplicateRoute(path.to_string()))
+            }
+            Some(node) => (node.methods.clone(), node.handler.clone()),
+            None => (Vec::new(), None),
+        };
+        let mut allowed = methods.clone();
+        allowed.push(method.clone());
+        let allow = allowed
+            .iter()
+            .map(|method| format!("{:?}", method))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let for_method = method;
+        let dispatch = Handler::Erased(Arc::new(move |req: NewRequestType, state, extracts| {
+            let handler = match &req.metadata.method {
+                method if *method == for_method => Some(handler.clone()),
+                method if methods.contains(method) => other.clone(),
+                _ => None,
+            };
+            let allow = allow.clone();
+            Box::pin(async move {
+                let handler = match handler {
+                    Some(handler) => handler,
+                    None => {
+                        return respond((
+                            StatusCode::METHOD_NOT_ALLOWED,
+                            HashMap::from([("Allow".to_string(), allow)]),
+                            String::new(),
+                        ))
+                    }
+                };
+                match handler.handle(req, state, extracts).await {
+                    Some(res) => res,
+                    None => respond(StatusCode::NOT_FOUND),
+                }
+            })
+        }));
+        let mut router = self.add_route(path, dispatch)?;
+        match router.routes.find_mut(path) {
+            Some(node) => node.methods = allowed,
+            None => return Err(RouteError::InvalidPath(path.to_string())),
+        }
+        Ok(router)
+    }
+    // Builds the url of a route registered with add_named, e.g.
+    // url_for("user_show", &[("id", "42")]) for /user/:id gives /user/42
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.names.url_for(name, params)
+    }
+    // Builds the url of a route registered with add_named on the router of a host
+    pub fn url_for_host(
+        &self,
+        host: &str,
+        name: &str,
+        params: &[(&str, &str)],
+    ) -> Result<String, UrlError> {
+        self.names.url_for_host(host, name, params)
+    }
+    // Mounts all routes of another router under prefix. The nested router can use its own state
+    // type as long as it can be derived from the state of this router
+    // e.g. Router<AdminState> nested in a Router<AppState> with AdminState: FromRef<AppState>
+    pub fn nest<S>(self, prefix: &str, router: Router<S>) -> std::result::Result<Self, RouteError>
+    where
+        S: FromRef<T> + Send + Sync + 'static,
+    {
+        let mut nested = self.nest_routes(prefix, router.routes, |state: &Option<State<T>>| {
+            state.as_ref().map(|state| state.substate())
+        })?;
+        nested.names.nest(prefix, router.names)?;
+        Ok(nested)
+    }
+    // Same as nest but the nested router keeps the state it was given with Router::with_state,
+    // so it does not need to know anything about the state of this router. A router without a
+    // state of its own that uses the same state type as this one gets the state of this router,
+    // the state type does not need to be Clone for that
+    pub fn nest_with_own_state<S>(
+        self,
+        prefix: &str,
+        router: Router<S>,
+    ) -> std::result::Result<Self, RouteError>
+    where
+        S: Send + Sync + 'static,
+    {
+        let own_state = router.routes.state.clone();
+        let mut nested =
+            self.nest_routes(prefix, router.routes, move |state: &Option<State<T>>| {
+                own_state
+                    .clone()
+                    .or_else(|| state.as_ref().and_then(|state| state.downcast()))
+            })?;
+        nested.names.nest(prefix, router.names)?;
+        Ok(nested)
+    }
+    fn nest_routes<S, F>(
+        self,
+        prefix: &str,
+        routes: Node<S>,
+        derive: F,
+    ) -> std::result::Result<Self, RouteError>
+    where
+        S: Send + Sync + 'static,
+        F: Fn(&Option<State<T>>) -> Option<State<S>> + Clone + Send + Sync + 'static,
+    {
+        let prefix = prefix.trim_end_matches('/');
+        let mut router = self;
+        for (path, handler) in routes.handlers() {
+            let full_path = match path.as_str() {
+                "/" if prefix.is_empty() => "/".to_string(),
+                "/" => prefix.to_string(),
+                _ => prefix.to_string() + path.as_str(),
+            };
+            if router
+                .routes
+                .find(full_path.as_str())
+                .is_some_and(|node| node.handler.is_some())
+            {
+                return Err(RouteError::DuplicateRoute(full_path));
+            }
+            let handler = Handler::derive_state(handler, derive.clone());
+            let node = routes.find(path.as_str());
+            router = match node.and_then(|node| node.config.clone()) {
+                Some(config) => {
+                    router.add_handler_with_config(full_path.as_str(), handler, config)?
+                }
+                None => router.add_route(full_path.as_str(), handler)?,
+            };
+            let methods = node.map(|node| node.methods.clone()).unwrap_or_default();
+            if let Some(node) = router.routes.find_mut(full_path.as_str()) {
+                node.methods = methods;
+            }
+        }
+        Ok(router)
+    }
+    // Serves requests for host with the routes of router, everything else keeps going to the
+    // routes of this router. A pattern like *.tenant.example.com matches every subdomain of
+    // tenant.example.com, the matched part is passed to the handlers as the subdomain extract.
+    // The router keeps its own fallback and uses its own state if it was given one. It can not
+    // have hosts of its own
+    pub fn host(
+        mut self,
+        pattern: &str,
+        router: Router<T>,
+    ) -> std::result::Result<Self, RouteError> {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        if pattern.is_empty()
+            || pattern.trim_start_matches('*').contains('*')
+            || pattern.contains(':')
+            || (pattern.starts_with('*') && !pattern.starts_with("*."))
+        {
+            return Err(RouteError::InvalidHost(pattern));
+        }
+        if let Some(nested) = router.hosts.first() {
+            return Err(RouteError::NestedHost(nested.pattern.clone()));
+        }
+        self.hosts.retain(|host| host.pattern != pattern);
+        self.names.hosts.insert(pattern.clone(), router.names.paths);
+        self.hosts.push(VirtualHost {
+            pattern,
+            routes: router.routes,
+            fallback: router.fallback,
+        });
+        self.hosts.sort_by_key(|host| {
+            (
+                host.pattern.starts_with('*'),
+                std::cmp::Reverse(host.pattern.len()),
+            )
+        });
+        Ok(self)
+    }
+    // Registers handlers declared with the route attributes,
+    // e.g. router.mount(routes![show_user, list_users])
+    pub fn mount(self, routes: Vec<RouteFn<T>>) -> std::result::Result<Self, RouteError> {
+        routes
+            .into_iter()
+            .try_fold(self, |router, route| route(router))
+    }
     pub fn fallback(mut self, func: Handler<T>) -> Self {
         self.fallback = Some(func);
         self
     }
     pub fn with_state(mut self, state: T) -> Self {
-        self.routes.state = Some(state);
+        self.routes.state = Some(State::new(state));
         self
     }
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+    // Every registered route, the ones of the router itself first and then the ones of each
+    // host, sorted by path
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let info = |host: Option<&str>, routes: &Node<T>| {
+            let mut infos: Vec<RouteInfo> = Vec::new();
+            for (path, handler) in routes.handlers() {
+                let name = match host {
+                    Some(host) => self.names.hosts.get(host),
+                    None => Some(&self.names.paths),
+                }
+                .and_then(|names| names.iter().find(|(_, named)| **named == path))
+                .map(|(name, _)| name.clone());
+                let methods = match routes.find(&path) {
+                    Some(node) if !node.methods.is_empty() => node
+                        .methods
+                        .iter()
+                        .map(|method| format!("{:?}", method))
+                        .collect(),
+                    _ => vec!["*".to_string()],
+                };
+                for method in methods {
+                    infos.push(RouteInfo {
+                        name: name.clone(),
+                        path: path.clone(),
+                        host: host.map(str::to_string),
+                        method,
+                        handler: handler.kind().to_string(),
+                        middleware: Vec::new(),
+                    });
+                }
+            }
+            infos.sort_by(|a, b| a.path.cmp(&b.path));
+            infos
+        };
+        let mut infos = info(None, &self.routes);
+        for vhost in self.hosts.iter() {
+            infos.extend(info(Some(&vhost.pattern), &vhost.routes));
+        }
+        infos
+    }
+    // Serves the route table at path, usually /__routes, as an html table for browsers and as
+    // json otherwise. The table is taken when this is called so it has to come after all
+    // other routes, hosts and nested routers
+    pub fn route_listing(mut self, path: &str) -> std::result::Result<Self, RouteError> {
+        // filled right after the handler is added so the listing includes itself
+        let infos: Arc<OnceLock<Vec<RouteInfo>>> = Arc::new(OnceLock::new());
+        let listed = Arc::clone(&infos);
+        let handler = Handler::Erased(Arc::new(move |req: NewRequestType, _, _| {
+            let infos = Arc::clone(&listed);
+            Box::pin(async move {
+                let infos = infos.get().cloned().unwrap_or_default();
+                let wants_html = req
+                    .headers
+                    .get("accept")
+                    .is_some_and(|accept| accept.contains("text/html"));
+                match wants_html {
+                    true => respond(Html(routes_table(&infos))),
+                    false => respond(Json(infos)),
+                }
+            })
+        }));
+        let router = self.add_route(path, handler)?;
+        let _ = infos.set(router.routes());
+        Ok(router)
+    }
 
     pub fn make_into_serveable(self) -> RouterServable<T> {
-        let boxed = Box::new(self);
-        let leaked = Box::leak(boxed);
-        RouterServable { router: leaked }
+        RouterServable {
+            table: RouteTable::new(self),
+        }
     }
 }
+// The router a server is serving, it can be replaced while the server is running, e.g. to turn
+// on feature flagged endpoints or load plugins without a restart. Every request is handled by
+// the router that was current when it started, so requests in flight finish on the old one and
+// the old router is dropped once the last of them is done
 #[derive(Debug)]
-pub struct RouterServable<T: Clone + Default + Send + std::marker::Sync + 'static> {
-    router: &'static Router<T>,
+pub struct RouteTable<T> {
+    router: Arc<ArcSwap<Router<T>>>,
+}
+// Implemented by hand since deriving would require T: Clone
+impl<T> Clone for RouteTable<T> {
+    fn clone(&self) -> Self {
+        RouteTable {
+            router: Arc::clone(&self.router),
+        }
+    }
+}
+impl<T> RouteTable<T>
+where
+    T: Sync,
+    T: Send,
+    T: 'static,
+{
+    pub fn new(router: Router<T>) -> Self {
+        RouteTable {
+            router: Arc::new(ArcSwap::from_pointee(router)),
+        }
+    }
+    pub fn current(&self) -> Arc<Router<T>> {
+        self.router.load_full()
+    }
+    // Installs router for all requests that start from now on and returns the one it replaced.
+    // The ServerConfig of the new router applies to new requests as well
+    pub fn swap(&self, router: Router<T>) -> Arc<Router<T>> {
+        self.router.swap(Arc::new(router))
+    }
+    // Serves one listener, if tls is set every connection does a tls handshake first
+    pub(crate) async fn serve_on(
+        self,
+        listener: Listener,
+        tls: Option<TlsAcceptor>,
+        handle: ServerHandle,
+    ) -> std::io::Result<()> {
+        let conn_handle = handle.clone();
+        serve_listener(listener, handle, move |socket| {
+            let tls = tls.clone();
+            let handle = conn_handle.clone();
+            let table = self.clone();
+            async move {
+                let res = match tls {
+                    None => handle_conn_node_based(socket, &table, &handle).await,
+                    Some(acceptor) => {
+                        // the handshake counts towards the header timeout
+                        let header_timeout = table.current().config.header_timeout;
+                        let deadline = conn::deadline(Instant::now(), header_timeout);
+                        let stream = match conn::until(deadline, acceptor.accept(socket)).await {
+                            Some(Ok(stream)) => stream,
+                            Some(Err(e)) => {
+                                eprintln!("Tls handshake failed: {e}");
+                                return;
+                            }
+                            None => return,
+                        };
+                        handle_conn_node_based(stream, &table, &handle).await
+                    }
+                };
+                if let Err(e) = res {
+                    eprintln!("Cannot handle incomming connection: {e}");
+                }
+            }
+        })
+        .await
+    }
+}
+fn routes_table(infos: &[RouteInfo]) -> String {
+    let mut table = String::from(
+        "<table><tr><th>Host</th><th>Method</th><th>Path</th><th>Name</th><th>Handler</th><th>Middleware</th></tr>",
+    );
+    for info in infos {
+        table += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape_html(info.host.as_deref().unwrap_or("")),
+            escape_html(&info.method),
+            escape_html(&info.path),
+            escape_html(info.name.as_deref().unwrap_or("")),
+            escape_html(&info.handler),
+            escape_html(&info.middleware.join(", ")),
+        );
+    }
+    table + "</table>"
+}
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+#[derive(Debug)]
+pub struct RouterServable<T: 'static> {
+    table: RouteTable<T>,
 }
 impl<T> RouterServable<T>
 where
     T: Sync,
-    T: Clone,
-    T: Default,
     T: Send,
-    T: std::fmt::Debug,
+    T: 'static,
 {
-    pub async fn serve(self, addr: &str) -> ! {
-        self.router.serve(addr).await
+    // Can be kept to replace the router while it is served
+    pub fn route_table(&self) -> RouteTable<T> {
+        self.table.clone()
+    }
+    // Binds the listener without serving yet, so the bound address can be read first.
+    // More listeners can be added to the returned Server, they all share the same routes
+    pub async fn bind(self, addr: &str) -> std::io::Result<Server<T>> {
+        Server::new(self.table).bind(addr).await
+    }
+    pub async fn bind_tls(self, addr: &str, path_to_cert: &str) -> std::io::Result<Server<T>> {
+        Server::new(self.table).bind_tls(addr, path_to_cert).await
+    }
+    // Binds a unix socket at path. A stale socket file left behind by a previous run is removed,
+    // mode sets the permissions of the socket file, e.g. 0o660 so only the group can connect
+    #[cfg(unix)]
+    pub fn bind_unix(
+        self,
+        path: impl AsRef<std::path::Path>,
+        mode: Option<u32>,
+    ) -> std::io::Result<Server<T>> {
+        Server::new(self.table).bind_unix(path, mode)
+    }
+    // Uses an already bound listener, for example one bound to port 0 in tests
+    pub fn with_listener(self, listener: impl Into<Listener>) -> Server<T> {
+        Server::new(self.table).with_listener(listener)
+    }
+    // Uses the sockets passed in by systemd instead of binding, they are taken with
+    // systemd::listeners_from_env before the runtime is started. Fails if there are none
+    #[cfg(unix)]
+    pub fn bind_activated(
+        self,
+        activated: Vec<crate::systemd::ActivatedListener>,
+    ) -> std::io::Result<Server<T>> {
+        let server = Server::new(self.table).with_activated_listeners(activated)?;
+        if server.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no sockets were passed with LISTEN_FDS",
+            ));
+        }
+        Ok(server)
+    }
+    // Same as bind_activated, the sockets named https are served over tls with the certificate
+    // at path_to_cert
+    #[cfg(unix)]
+    pub fn bind_activated_tls(
+        self,
+        activated: Vec<crate::systemd::ActivatedListener>,
+        path_to_cert: &str,
+    ) -> std::io::Result<Server<T>> {
+        let server =
+            Server::new(self.table).with_activated_tls_listeners(activated, path_to_cert)?;
+        if server.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no sockets were passed with LISTEN_FDS",
+            ));
+        }
+        Ok(server)
+    }
+    // Uses the sockets passed in by systemd if there are any, otherwise binds addr.
+    // Handy to run the same binary with and without socket activation
+    #[cfg(unix)]
+    pub async fn bind_activated_or(
+        self,
+        activated: Vec<crate::systemd::ActivatedListener>,
+        addr: &str,
+    ) -> std::io::Result<Server<T>> {
+        let server = Server::new(self.table).with_activated_listeners(activated)?;
+        if server.is_empty() {
+            return server.bind(addr).await;
+        }
+        Ok(server)
     }
-    pub async fn serve_tls(self, addr: &str, path_to_cert: &str) -> ! {
-        self.router.serve_tls(addr, path_to_cert).await
+    pub async fn serve(self, addr: &str) -> std::io::Result<()> {
+        self.bind(addr).await?.serve().await
+    }
+    pub async fn serve_on(self, listener: impl Into<Listener>) -> std::io::Result<()> {
+        self.with_listener(listener).serve().await
+    }
+    #[cfg(unix)]
+    pub async fn serve_unix(self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        self.bind_unix(path, None)?.serve().await
+    }
+    pub async fn serve_tls(self, addr: &str, path_to_cert: &str) -> std::io::Result<()> {
+        self.bind(addr).await?.serve_tls(path_to_cert).await
+    }
+    // Serves until handle.shutdown() is called, then drains the open connections and returns
+    pub async fn serve_with_handle(self, addr: &str, handle: ServerHandle) -> std::io::Result<()> {
+        self.bind(addr).await?.with_handle(handle).serve().await
+    }
+    pub async fn serve_tls_with_handle(
+        self,
+        addr: &str,
+        path_to_cert: &str,
+        handle: ServerHandle,
+    ) -> std::io::Result<()> {
+        self.bind(addr)
+            .await?
+            .with_handle(handle)
+            .serve_tls(path_to_cert)
+            .await
+    }
+    // Serves until signal resolves, for example httpRs::server::shutdown_signal()
+    pub async fn serve_with_shutdown(
+        self,
+        addr: &str,
+        signal: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        self.bind(addr).await?.serve_with_shutdown(signal).await
+    }
+    pub async fn serve_tls_with_shutdown(
+        self,
+        addr: &str,
+        path_to_cert: &str,
+        signal: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        self.bind(addr)
+            .await?
+            .serve_tls_with_shutdown(path_to_cert, signal)
+            .await
     }
 }
 
-#[derive(Debug, Default)]
-pub struct Node<T: Clone + Default + Send + std::marker::Sync> {
+#[derive(Debug)]
+pub struct Node<T> {
     pub subpath: String,
     pub children: Option<Box<Vec<Box<Node<T>>>>>,
     pub handler: Option<Handler<T>>,
-    pub state: Option<T>,
+    pub state: Option<State<T>>,
+    pub config: Option<RouteConfig>,
+    // Methods registered with Router::add_method_handler, empty if the handler takes every method
+    pub methods: Vec<Method>,
+}
+// Implemented by hand since deriving would require T: Clone, the state is shared
+impl<T> Clone for Node<T> {
+    fn clone(&self) -> Self {
+        Node {
+            subpath: self.subpath.clone(),
+            children: self.children.clone(),
+            handler: self.handler.clone(),
+            state: self.state.clone(),
+            config: self.config.clone(),
+            methods: self.methods.clone(),
+        }
+    }
+}
+// The tree relies on std::mem::take so it needs a Default that does not require T: Default
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            subpath: String::new(),
+            children: None,
+            handler: None,
+            state: None,
+            config: None,
+            methods: Vec::new(),
+        }
+    }
 }
 impl<T> Node<T>
 where
     T: Sync,
-    T: Clone,
-    T: Default,
     T: Send,
-    T: std::fmt::Debug,
+    T: 'static,
 {
     pub fn new(path: &str) -> Self {
         Node {
@@ -355,40 +1098,76 @@ where
             children: None,
             handler: None,
             state: None,
+            config: None,
+            methods: Vec::new(),
         }
     }
     pub fn add_state(&mut self, state: T) -> Self {
-        self.state = Some(state);
+        self.state = Some(State::new(state));
         return std::mem::take(self);
     }
-    pub fn make_into_serveable(self) -> &'static mut Self {
-        let box_self = Box::new(self);
-        Box::leak(box_self)
+    // Serves the tree as the routes of a Router without a fallback or other hosts
+    pub fn make_into_serveable(self) -> RouterServable<T> {
+        Router {
+            routes: self,
+            ..Router::default()
+        }
+        .make_into_serveable()
+    }
+    pub async fn serve(self, addr: &str) -> std::io::Result<()> {
+        self.make_into_serveable().serve(addr).await
     }
-    pub async fn serve(&'static self, addr: &str) -> ! {
-        let listener = match TcpListener::bind(addr).await {
-            Ok(listener) => listener,
-            Err(e) => panic!("Cannot create listener Error: {e} "),
+    // Every registered path together with its handler, the paths are the full paths of the nodes
+    pub fn handlers(&self) -> Vec<(String, Handler<T>)> {
+        let mut handlers = Vec::new();
+        if let Some(handler) = &self.handler {
+            handlers.push((self.subpath.clone(), handler.clone()));
+        }
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                handlers.extend(child.handlers());
+            }
+        }
+        handlers
+    }
+    // The node that was registered with exactly this path
+    pub fn find(&self, path: &str) -> Option<&Node<T>> {
+        if self.subpath == path {
+            return Some(self);
+        }
+        self.children
+            .as_ref()?
+            .iter()
+            .find_map(|child| child.find(path))
+    }
+    pub fn find_mut(&mut self, path: &str) -> Option<&mut Node<T>> {
+        if self.subpath == path {
+            return Some(self);
+        }
+        self.children
+            .as_mut()?
+            .iter_mut()
+            .find_map(|child| child.find_mut(path))
+    }
+    // Looks up path like get_handler but applies the trailing slash policy
+    pub fn route(&self, path: &str, policy: TrailingSlash) -> Routed<T> {
+        // pub_walk lets /user/5/ match /user/:id so the trailing slash is compared separately
+        let lookup = |path: &str| {
+            self.get_handler(path.to_string())
+                .filter(|res| path == "/" || res.route.ends_with('/') == path.ends_with('/'))
         };
-        loop {
-            let (socket, _) = match listener.accept().await {
-                Ok((socket, other_thing)) => (socket, other_thing),
-                Err(e) => panic!("Canot accept connection Error: {e}"),
-            };
-
-            tokio::spawn(async move {
-                //                                                Might want to avoid cloning the
-                //                                                state for every connection, maybe
-                //                                                an Arc::clone would be better since it
-                //                                                does not create new memory
-                //
-                match handle_conn_node_based(socket, &self, None, self.state.clone()).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        panic!("Cannot handle incomming connection: {e} \n")
-                    }
-                }
-            });
+        if let Some(res) = lookup(path) {
+            return Routed::Found(res);
+        }
+        let other = match path.strip_suffix('/') {
+            Some("") => return Routed::NotFound,
+            Some(path) => path.to_string(),
+            None => path.to_string() + "/",
+        };
+        match (policy, lookup(&other)) {
+            (TrailingSlash::Strict, _) | (_, None) => Routed::NotFound,
+            (TrailingSlash::Permissive, Some(res)) => Routed::Found(res),
+            (TrailingSlash::Redirect, Some(_)) => Routed::Redirect(other),
         }
     }
     pub fn get_handler(&self, path: String) -> Option<RoutingResult<T>> {
@@ -399,6 +1178,8 @@ where
                         // Would like to avoid cloing this for every connection
                         handler: handler.clone(),
                         extract: None,
+                        config: self.config.clone(),
+                        route: self.subpath.clone(),
                     });
                 }
                 None => return None,
@@ -418,37 +1199,20 @@ where
             self.handler = Some(handler);
             return Ok(Box::new(std::mem::take(self)));
         }
-        for i in LIST_UNSUPPORTED.into_iter() {
-            if path.contains(*i) {
-                panic!(
-                    "Your path contains a value that is unsupported. Char: {}",
-                    i
-                );
-            }
+        if path.contains(LIST_UNSUPPORTED) {
+            return Err(());
+        }
+        // request paths never contain them after normalization so the route could not match
+        if path
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+        {
+            return Err(());
         }
 
-        let res = pub_walk_add_node(self, path.to_string(), handler);
-        if let Some((node, ok)) = res {
-            if ok {
-                match self.children.as_mut() {
-                    None => self.children = Some(Box::new(vec![node])),
-                    Some(vec) => {
-                        // this is ugly
-                        vec.push(node);
-                        // this is due to the std::mem::take that leaves a default in there
-                        if let Some(first_node) = vec.get(0) {
-                            if first_node.subpath == "" {
-                                vec.remove(0);
-                            }
-                        }
-                        return Ok(Box::new(std::mem::take(self)));
-                    }
-                }
-                return Ok(Box::new(std::mem::take(self)));
-            }
-            return Ok(node);
-        } else {
-            return Ok(Box::new(std::mem::take(self)));
+        match pub_walk_add_node(self, path.to_string(), handler) {
+            Some(node) => Ok(node),
+            None => Ok(Box::new(std::mem::take(self))),
         }
     }
     pub fn insert(&mut self, path: String, path_rn: String, func: Handler<T>) -> Box<Node<T>> {
@@ -491,8 +1255,9 @@ where
                     false => path_rn.clone() + "/" + to_add_to_curr.as_str(),
                     true => path_rn.clone() + to_add_to_curr.as_str(),
                 };
+                // only a trailing slash is missing, e.g. /users/ below /users
                 if to_add_to_curr == "" {
-                    final_path = missing_part_of_path;
+                    final_path = path.clone();
                 }
                 final_path
             }
@@ -517,17 +1282,11 @@ where
     }
 }
 
-fn pub_walk_add_node<
-    T: std::default::Default
-        + std::clone::Clone
-        + std::marker::Send
-        + std::marker::Sync
-        + std::fmt::Debug,
->(
+fn pub_walk_add_node<T: std::marker::Send + std::marker::Sync + 'static>(
     node: &mut Node<T>,
     path: String,
     func: Handler<T>,
-) -> Option<(Box<Node<T>>, bool)> {
+) -> Option<Box<Node<T>>> {
     match node.children.as_mut() {
         Some(children) => {
             let mut matches = 0;
@@ -535,7 +1294,7 @@ fn pub_walk_add_node<
                 let child = children.get_mut(i)?;
                 if child.subpath == path {
                     child.handler = Some(func);
-                    return Some((Box::new(std::mem::take(node)), false));
+                    return Some(Box::new(std::mem::take(node)));
                 }
                 let test_str = child.subpath.clone() + "/";
                 if path.starts_with(test_str.as_str()) {
@@ -547,8 +1306,13 @@ fn pub_walk_add_node<
                     // this is not wanted since you obv should not append to /wowo
                     matches = matches + 1;
 
+                    // the child comes back with the new route added, it has to be put back
+                    // in its place and the tree is returned from the top again
                     match pub_walk_add_node(child, path.clone(), func) {
-                        Some((node, ok)) => return Some((node, ok)),
+                        Some(updated) => {
+                            **child = *updated;
+                            return Some(Box::new(std::mem::take(node)));
+                        }
                         None => return None,
                     };
                 }
@@ -557,7 +1321,7 @@ fn pub_walk_add_node<
                 let node_path_curr = node.subpath.clone();
                 let node = node.insert(path, node_path_curr, func);
 
-                return Some((node, true));
+                return Some(node);
             }
             return None;
         }
@@ -565,14 +1329,12 @@ fn pub_walk_add_node<
             let node_path_curr = node.subpath.clone();
             //let node = node.insert(path, node_path_curr, func);
             let node = node.insert(path, node_path_curr, func);
-            return Some((node, false));
+            return Some(node);
         }
     }
 }
 
-fn pub_walk<
-    T: std::default::Default + std::clone::Clone + std::marker::Send + std::marker::Sync,
->(
+fn pub_walk<T>(
     children: &Option<Box<Vec<Box<Node<T>>>>>,
     path: String,
 ) -> Option<RoutingResult<T>> {
@@ -625,6 +1387,8 @@ fn pub_walk<
                         // Would like to avoid cloing this for every connection
                         handler: handler.clone(),
                         extract: Some(extracts),
+                        config: child.config.clone(),
+                        route: child.subpath.clone(),
                     });
                 }
                 // This is the identifier to the extract. For instance if we registered the route
@@ -651,6 +1415,8 @@ fn pub_walk<
                             return Some(RoutingResult {
                                 handler: handler.clone(),
                                 extract: Some(extracts),
+                                config: child.config.clone(),
+                                route: child.subpath.clone(),
                             });
                         }
                         None => {
@@ -670,6 +1436,8 @@ fn pub_walk<
                             // Would like to avoid cloing this for every connection
                             handler: handler.clone(),
                             extract: None,
+                            config: child.config.clone(),
+                            route: child.subpath.clone(),
                         });
                     }
                     None => (),
@@ -686,90 +1454,487 @@ fn pub_walk<
     }
     None
 }
-pub async fn send_error_response(mut socket: TcpStream, code: StatusCode) -> std::io::Result<()> {
-    let res = code.into_response();
+// Errors like EMFILE or ECONNABORTED only affect a single accept call,
+// the listener itself is still usable so we wait a bit and try again
+pub fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    match e.kind() {
+        std::io::ErrorKind::ConnectionAborted
+        | std::io::ErrorKind::ConnectionReset
+        | std::io::ErrorKind::ConnectionRefused
+        | std::io::ErrorKind::Interrupted
+        | std::io::ErrorKind::WouldBlock
+        | std::io::ErrorKind::TimedOut
+        | std::io::ErrorKind::OutOfMemory => true,
+        _ => is_out_of_resources(e),
+    }
+}
+// ENFILE, EMFILE, ENOBUFS or ENOMEM, their numbers differ between the unix systems
+#[cfg(unix)]
+fn is_out_of_resources(e: &std::io::Error) -> bool {
+    use nix::errno::Errno;
+    matches!(
+        e.raw_os_error().map(Errno::from_i32),
+        Some(Errno::ENFILE | Errno::EMFILE | Errno::ENOBUFS | Errno::ENOMEM)
+    )
+}
+#[cfg(not(unix))]
+fn is_out_of_resources(_e: &std::io::Error) -> bool {
+    false
+}
+const ACCEPT_BACKOFF_START: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+pub struct AcceptBackoff {
+    delay: Duration,
+}
+impl AcceptBackoff {
+    pub fn new() -> Self {
+        AcceptBackoff {
+            delay: ACCEPT_BACKOFF_START,
+        }
+    }
+    // Retries transient accept errors with an exponential backoff,
+    // any other error is returned to the caller
+    pub async fn accept(&mut self, listener: &Listener) -> std::io::Result<Stream> {
+        loop {
+            match listener.accept().await {
+                Ok(conn) => {
+                    self.delay = ACCEPT_BACKOFF_START;
+                    return Ok(conn);
+                }
+                Err(e) if is_transient_accept_error(&e) => {
+                    eprintln!(
+                        "Cannot accept connection, retrying in {:?} Error: {e}",
+                        self.delay
+                    );
+                    tokio::time::sleep(self.delay).await;
+                    self.delay = std::cmp::min(self.delay * 2, ACCEPT_BACKOFF_MAX);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+// Sends code and closes the connection
+pub async fn send_error_response<S: Connection>(
+    socket: S,
+    code: StatusCode,
+) -> std::io::Result<()> {
+    send_error_response_for(socket, code, Version::HTTP_11).await
+}
+// Same as send_error_response with the status line matching the version of the request
+pub async fn send_error_response_for<S: Connection>(
+    mut socket: S,
+    code: StatusCode,
+    version: Version,
+) -> std::io::Result<()> {
+    let res = conn::with_version(code.into_response(), version);
+    let res = conn::with_header(res, "Connection: close");
     socket.write_all(res.as_slice()).await?;
     socket.flush().await?;
     socket.shutdown().await?;
     Ok(())
 }
-pub async fn handle_conn_node_based<
-    T: std::clone::Clone
-        + std::default::Default
-        + std::marker::Send
-        + std::marker::Sync
-        + std::fmt::Debug,
->(
-    mut socket: TcpStream,
-    handlers: &Node<T>,
-    fallback: Option<Handler<T>>,
-    state: Option<T>,
-) -> std::io::Result<()> {
-    // did this to avoid leftover zeros at the end;
-    let mut buf = Vec::with_capacity(1024);
-    socket.read_buf(&mut buf).await?;
-    let req_str = String::from_utf8_lossy(&buf[..]);
-    let res = parse::parse_request(&req_str).unwrap();
-
-    let routing_res: RoutingResult<T> = match handlers.get_handler(res.metadata.path.clone()) {
-        Some(res) => res,
-        None => match fallback {
-            Some(fallback) => {
-                let res = match fallback.handle(res, state, None).await {
-                    Some(res) => res,
-                    None => {
-                        send_error_response(socket, StatusCode::NOT_FOUND).await?;
-                        return Ok(());
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+// Whether the client waits for a 100 Continue before it sends the body.
+// 100-continue is the only expectation there is, anything else gets a 417.
+// HTTP/1.0 clients do not know about it so the header is ignored for them
+fn expectation(req: &NewRequestType, version: Version) -> Result<bool, StatusCode> {
+    if version == Version::HTTP_10 {
+        return Ok(false);
+    }
+    match req.headers.get("expect") {
+        None => Ok(false),
+        Some(expect) if expect.trim().eq_ignore_ascii_case("100-continue") => Ok(true),
+        Some(_) => Err(StatusCode::EXPECTATION_FAILED),
+    }
+}
+// HTTP/1.0 or HTTP/1.1, later 1.x versions are handled like 1.1 and other major versions get
+// a 505. The lenient parser lets any version string through, those are treated as 1.1
+fn request_version(req: &NewRequestType) -> Result<Version, StatusCode> {
+    let version = match req.metadata.version.strip_prefix("HTTP/") {
+        Some(version) => version,
+        None => return Ok(Version::HTTP_11),
+    };
+    match version.split_once('.').map_or(version, |(major, _)| major) {
+        "1" if version == "1.0" => Ok(Version::HTTP_10),
+        "1" => Ok(Version::HTTP_11),
+        _ => Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+    }
+}
+// The client asked to close the connection after this request.
+// HTTP/1.1 connections are kept alive unless the client sends Connection: close,
+// HTTP/1.0 connections are closed unless the client sends Connection: keep-alive
+fn wants_close(req: &NewRequestType, version: Version) -> bool {
+    let has_option = |wanted: &str| match req.headers.get("connection") {
+        Some(val) => val
+            .split(',')
+            .any(|option| option.trim().eq_ignore_ascii_case(wanted)),
+        None => false,
+    };
+    match version {
+        Version::HTTP_10 => !has_option("keep-alive"),
+        _ => has_option("close"),
+    }
+}
+// Serves requests on one connection until the client closes it, asks for it to be closed,
+// stays idle for longer than the keep-alive timeout or the server shuts down
+pub async fn handle_conn_node_based<S, T>(
+    mut socket: S,
+    table: &RouteTable<T>,
+    handle: &ServerHandle,
+) -> std::io::Result<()>
+where
+    S: Connection,
+    T: std::marker::Send + std::marker::Sync + 'static,
+{
+    let peer_credentials = socket.peer_credentials();
+    // Holds everything read from the socket that was not used yet. A pipelining client sends
+    // the next requests without waiting for the responses, whatever is left after one request
+    // is the start of the next. The requests are handled one after the other so the responses
+    // go out in the order the requests came in
+    let mut buf = BytesMut::with_capacity(1024);
+    let mut first_request = true;
+    loop {
+        // a router swapped in while a request is handled is used from the next request on
+        let router = table.current();
+        let config = &router.config;
+        let started = Instant::now();
+        // nothing of the next request has been read yet, so the connection is idle and can be
+        // closed right away on shutdown
+        if buf.is_empty() {
+            let idle_timeout = match first_request {
+                true => config.header_timeout,
+                false => config.keep_alive_timeout,
+            };
+            let read = tokio::select! {
+                res = conn::read_more(&mut socket, &mut buf, conn::deadline(started, idle_timeout)) => res?,
+                _ = handle.stopped() => return Ok(()),
+            };
+            match read {
+                Read::Done(()) => (),
+                Read::Closed => return Ok(()),
+                Read::TimedOut if first_request => {
+                    return send_error_response(socket, StatusCode::REQUEST_TIMEOUT).await
+                }
+                Read::TimedOut => return Ok(()),
+                Read::Rejected(status) => return send_error_response(socket, status).await,
+            }
+        }
+        // for the first request the header timeout already started when it was accepted
+        let header_start = match first_request {
+            true => started,
+            false => Instant::now(),
+        };
+        first_request = false;
+        let head_deadline = conn::deadline(header_start, config.header_timeout);
+        let limits = &config.route.limits;
+        let head_read = conn::read_head(
+            &mut socket,
+            &mut buf,
+            head_deadline,
+            limits,
+            config.parse_mode,
+        );
+        let head_len = match head_read.await? {
+            Read::Done(len) => len,
+            Read::Closed => return Ok(()),
+            Read::TimedOut => {
+                return send_error_response(socket, StatusCode::REQUEST_TIMEOUT).await
+            }
+            Read::Rejected(status) => return send_error_response(socket, status).await,
+        };
+        let head = buf.split_to(head_len);
+        let parsed = match config.parse_mode {
+            ParseMode::Strict => parse::parse_request_strict(&head),
+            ParseMode::Lenient => parse::parse_request(&String::from_utf8_lossy(&head[..])),
+        };
+        let mut res = match parsed {
+            Ok(res) => res,
+            Err(ParseError::UnsupportedTransferEncoding) => {
+                return send_error_response(socket, StatusCode::NOT_IMPLEMENTED).await
+            }
+            Err(_) => return send_error_response(socket, StatusCode::BAD_REQUEST).await,
+        };
+        res.peer_credentials = peer_credentials;
+        res.uri = parse::effective_uri(&res, socket.scheme());
+        let version = match request_version(&res) {
+            Ok(version) => version,
+            Err(status) => return send_error_response(socket, status).await,
+        };
+        // HTTP/1.1 clients have to send Host (RFC 9112 3.2), HTTP/1.0 ones may leave it out
+        if version != Version::HTTP_10 && !res.headers.contains_key("host") {
+            return send_error_response_for(socket, StatusCode::BAD_REQUEST, version).await;
+        }
+        // the host picks the routes before the path is looked at, requests for a host
+        // without a router of its own use the routes of the router itself
+        let (routes, fallback, state, host_extracts) = match res
+            .uri
+            .host()
+            .and_then(|host| find_host(&router.hosts, host))
+        {
+            Some((vhost, captured)) => (
+                &vhost.routes,
+                vhost.fallback.clone(),
+                vhost
+                    .routes
+                    .state
+                    .clone()
+                    .or_else(|| router.routes.state.clone()),
+                captured,
+            ),
+            None => (
+                &router.routes,
+                router.fallback.clone(),
+                router.routes.state.clone(),
+                HashMap::new(),
+            ),
+        };
+        // routes are matched on the normalized path, CONNECT and OPTIONS * have none
+        if res.metadata.path.starts_with('/') {
+            res.metadata.path =
+                match parse::normalize_path(&res.metadata.path, config.merge_slashes) {
+                    Ok(path) => path,
+                    Err(_) => {
+                        return send_error_response_for(socket, StatusCode::BAD_REQUEST, version)
+                            .await
                     }
                 };
-                let resp = res.into_response();
-                socket.write_all(resp.as_slice()).await?;
-                socket.flush().await?;
-                socket.shutdown().await?;
-                return Ok(());
-            }
-            None => {
-                send_error_response(socket, StatusCode::NOT_FOUND).await?;
-                return Ok(());
+        }
+        // chunked bodies are not supported yet, without knowing where the body ends the
+        // connection can not be used for another request either
+        if res.headers.contains_key("transfer-encoding") {
+            return send_error_response_for(socket, StatusCode::NOT_IMPLEMENTED, version).await;
+        }
+        let content_length = match res.headers.get("content-length") {
+            Some(len) => match len.trim().parse::<usize>() {
+                Ok(len) => len,
+                Err(_) => {
+                    return send_error_response_for(socket, StatusCode::BAD_REQUEST, version).await
+                }
+            },
+            None => 0,
+        };
+
+        // CONNECT and OPTIONS * have no path that could match a route, they would otherwise
+        // end up at the trailing slash policy as if they were the empty path
+        let routed = match res.metadata.path.starts_with('/') {
+            true => routes.route(&res.metadata.path, config.trailing_slash),
+            false => Routed::NotFound,
+        };
+        let routing_res = match routed {
+            Routed::Found(routing_res) => Some(routing_res),
+            Routed::NotFound => None,
+            Routed::Redirect(path) => {
+                let location = match res.uri.query() {
+                    Some(query) => parse::percent_encode_path(&path) + "?" + query,
+                    None => parse::percent_encode_path(&path),
+                };
+                let response = (
+                    StatusCode::PERMANENT_REDIRECT,
+                    HashMap::from([("Location".to_string(), location)]),
+                    String::new(),
+                )
+                    .into_response();
+                // a body that was sent along is not read, the client sends it again to the new location
+                let close = content_length > 0 || wants_close(&res, version);
+                if send_response(&mut socket, response, version, close, handle).await? {
+                    return Ok(());
+                }
+                continue;
             }
-        },
-    };
-    let handler = routing_res.handler;
+        };
+        let route_config = match routing_res.as_ref().and_then(|res| res.config.as_ref()) {
+            Some(route_config) => route_config.or(&config.route),
+            None => config.route.clone(),
+        };
+        // the route may have lower limits than the server, the body is rejected before it is read
+        if let Err(status) = conn::check_head(&head, &route_config.limits) {
+            return send_error_response_for(socket, status, version).await;
+        }
+        if exceeds(content_length, route_config.limits.body) {
+            return send_error_response_for(socket, StatusCode::PAYLOAD_TOO_LARGE, version).await;
+        }
+        let expects_continue = match expectation(&res, version) {
+            Ok(expects_continue) => expects_continue && content_length > 0,
+            Err(status) => return send_error_response_for(socket, status, version).await,
+        };
+        // nothing would read the body, the client does not have to send it at all
+        if expects_continue && routing_res.is_none() && fallback.is_none() {
+            return send_error_response_for(socket, StatusCode::NOT_FOUND, version).await;
+        }
+        // the body of a client that waits for a 100 is only read once the handler asks for it
+        let (pending_body, mut body_wanted) = conn::PendingBody::channel();
+        if expects_continue {
+            res.pending_body = Some(pending_body);
+        }
+        if content_length > 0 && !expects_continue {
+            let body_deadline = conn::deadline(Instant::now(), route_config.body_timeout);
+            let body = conn::read_body(
+                &mut socket,
+                &mut buf,
+                content_length,
+                body_deadline,
+                route_config.min_body_rate,
+            )
+            .await?;
+            res.body = match body {
+                Read::Done(body) => Some(body),
+                Read::Closed => return Ok(()),
+                Read::TimedOut => {
+                    return send_error_response_for(socket, StatusCode::REQUEST_TIMEOUT, version)
+                        .await
+                }
+                Read::Rejected(status) => {
+                    return send_error_response_for(socket, status, version).await
+                }
+            };
+        }
+        let mut close = wants_close(&res, version);
 
-    // will try to find another solution other to cloning this map
-    let map_clone = res.params.clone();
-    let res = match handler
-        .handle(
-            res,
-            state,
+        let (handler, extracts) = match routing_res {
             // This is needed since there are two ways extracts can be added to the request
             // The first being for example /user/:id which comes from the router
             // And the second being on the end of the request path for example
             // /user/:id?page=10
             // This gets parsed by the request parser so we need to merge the two maps
-            match routing_res.extract {
-                Some(mut res) => match map_clone {
-                    Some(map2) => {
-                        res.extend(map2.into_iter());
-                        Some(res)
+            Some(routing_res) => {
+                let extracts = match routing_res.extract {
+                    Some(extracts) => {
+                        // the routed path still has %2F and %25 encoded
+                        let mut extracts: HashMap<String, String> = extracts
+                            .into_iter()
+                            .map(|(key, val)| (key, parse::percent_decode(&val)))
+                            .collect();
+                        if let Some(params) = res.params.clone() {
+                            extracts.extend(params);
+                        }
+                        Some(extracts)
+                    }
+                    None => None,
+                };
+                (Some(routing_res.handler), extracts)
+            }
+            None => (fallback, None),
+        };
+        // the subdomain matched by a wildcard host, the path extracts win if a name is used twice
+        let extracts = match extracts {
+            _ if host_extracts.is_empty() => extracts,
+            Some(extracts) => Some(host_extracts.into_iter().chain(extracts).collect()),
+            None => Some(host_extracts),
+        };
+        let path = res.metadata.path.clone();
+        let mut handler_deadline = conn::deadline(Instant::now(), route_config.handler_timeout);
+        let mut waiting_for_body = expects_continue;
+        let mut body_read = false;
+        let response = match handler {
+            Some(handler) => {
+                let mut handled = std::pin::pin!(handler.handle(res, state.clone(), extracts));
+                let handled = loop {
+                    tokio::select! {
+                        handled = conn::until(handler_deadline, &mut handled) => break handled,
+                        reply = &mut body_wanted, if waiting_for_body => {
+                            waiting_for_body = false;
+                            let reply = match reply {
+                                Ok(reply) => reply,
+                                // the request was dropped without reading the body
+                                Err(_) => continue,
+                            };
+                            let read_started = Instant::now();
+                            // a client that did not wait for the 100 does not need it anymore
+                            if buf.is_empty() {
+                                socket.write_all(CONTINUE).await?;
+                                socket.flush().await?;
+                            }
+                            let body_deadline =
+                                conn::deadline(read_started, route_config.body_timeout);
+                            let body = conn::read_body(
+                                &mut socket,
+                                &mut buf,
+                                content_length,
+                                body_deadline,
+                                route_config.min_body_rate,
+                            )
+                            .await?;
+                            // the handler is dropped if the body can not be read
+                            match body {
+                                Read::Done(body) => {
+                                    body_read = true;
+                                    let _ = reply.send(body);
+                                }
+                                Read::Closed => return Ok(()),
+                                Read::TimedOut => {
+                                    return send_error_response_for(
+                                        socket,
+                                        StatusCode::REQUEST_TIMEOUT,
+                                        version,
+                                    )
+                                    .await
+                                }
+                                Read::Rejected(status) => {
+                                    return send_error_response_for(socket, status, version).await
+                                }
+                            }
+                            // waiting for the client does not count against the handler
+                            handler_deadline =
+                                handler_deadline.map(|deadline| deadline + read_started.elapsed());
+                        }
                     }
+                };
+                match handled {
+                    Some(Some(res)) => res.into_response(),
+                    Some(None) => StatusCode::NOT_FOUND.into_response(),
+                    None => {
+                        eprintln!("Handler for {path} did not finish in time");
+                        let status = route_config
+                            .handler_timeout_status
+                            .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+                        return send_error_response_for(socket, status, version).await;
+                    }
+                }
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        };
+        // the client may still send the body that was never asked for, so where the next
+        // request starts is unknown
+        if expects_continue && !body_read {
+            close = true;
+        }
 
-                    None => Some(res),
-                },
-                None => None,
-            },
-        )
-        .await
-    {
-        Some(res) => res,
-        None => return send_error_response(socket, StatusCode::NOT_FOUND).await,
+        if send_response(&mut socket, response, version, close, handle).await? {
+            return Ok(());
+        }
+    }
+}
+// Writes the response of a request that was read completely. Returns true if the connection
+// was closed, either because close was asked for or the connection can not be reused
+async fn send_response<S: Connection>(
+    socket: &mut S,
+    response: Vec<u8>,
+    version: Version,
+    close: bool,
+    handle: &ServerHandle,
+) -> std::io::Result<bool> {
+    // chunked responses are not used, so a body without a length ends when the connection does
+    let close = close || handle.is_shutting_down() || !conn::has_content_length(&response);
+    let response = conn::with_version(response, version);
+    if close {
+        let response = conn::with_header(response, "Connection: close");
+        socket.write_all(response.as_slice()).await?;
+        socket.flush().await?;
+        socket.shutdown().await?;
+        return Ok(true);
+    }
+    // keep-alive is not the default for HTTP/1.0 so it has to be confirmed
+    let response = match version {
+        Version::HTTP_10 => conn::with_header(response, "Connection: keep-alive"),
+        _ => response,
     };
-    let response = res.into_response();
-
     socket.write_all(response.as_slice()).await?;
-
     socket.flush().await?;
-
-    socket.shutdown().await?;
-    Ok(())
+    Ok(false)
 }
@@ -0,0 +1,583 @@
+#![forbid(unsafe_code)]
+use crate::router::AcceptBackoff;
+use crate::router::RouteTable;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// Credentials of the process on the other end of a unix socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+// Everything a request can be read from and a response written to.
+// Transports that know who is connected can report it with peer_credentials
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        None
+    }
+    // Scheme of the effective request uri of requests on this connection
+    fn scheme(&self) -> http::uri::Scheme {
+        http::uri::Scheme::HTTP
+    }
+}
+impl Connection for TcpStream {}
+impl Connection for tokio::io::DuplexStream {}
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        let cred = self.peer_cred().ok()?;
+        Some(PeerCredentials {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+}
+impl<S: Connection> Connection for tokio_rustls::server::TlsStream<S> {
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.get_ref().0.peer_credentials()
+    }
+    fn scheme(&self) -> http::uri::Scheme {
+        http::uri::Scheme::HTTPS
+    }
+}
+
+// A listener the server can accept connections on
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+impl Listener {
+    pub async fn accept(&self) -> std::io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Stream::Unix(listener.accept().await?.0)),
+        }
+    }
+    // Only tcp listeners have a socket address, unix listeners are bound to a path
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "unix listeners do not have a socket address",
+            )),
+        }
+    }
+}
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Listener {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+// A connection accepted by a Listener
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+impl Connection for Stream {
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_credentials(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.peer_credentials(),
+        }
+    }
+}
+// Both streams are Unpin so we can just forward to them
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// Handle to a running server. Calling shutdown stops accepting new connections,
+// closes connections that have not sent a request yet and waits for in-flight requests
+// to finish. Requests still running after the grace period are aborted.
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    inner: Arc<HandleInner>,
+}
+#[derive(Debug)]
+struct HandleInner {
+    shutdown: watch::Sender<bool>,
+    grace_period: Duration,
+    local_addrs: Mutex<Vec<SocketAddr>>,
+}
+impl ServerHandle {
+    pub fn new() -> Self {
+        Self::with_grace_period(DEFAULT_GRACE_PERIOD)
+    }
+    pub fn with_grace_period(grace_period: Duration) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        ServerHandle {
+            inner: Arc::new(HandleInner {
+                shutdown,
+                grace_period,
+                local_addrs: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+    pub fn shutdown(&self) {
+        self.inner.shutdown.send_replace(true);
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.shutdown.borrow()
+    }
+    pub fn grace_period(&self) -> Duration {
+        self.inner.grace_period
+    }
+    // Address of the first listener bound for this handle, known as soon as the server is bound
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs().first().copied()
+    }
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        match self.inner.local_addrs.lock() {
+            Ok(addrs) => addrs.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+    fn add_local_addr(&self, addr: SocketAddr) {
+        match self.inner.local_addrs.lock() {
+            Ok(mut addrs) => addrs.push(addr),
+            Err(poisoned) => poisoned.into_inner().push(addr),
+        }
+    }
+    // Resolves once shutdown was called
+    pub async fn stopped(&self) {
+        let mut rx = self.inner.shutdown.subscribe();
+        // The sender lives as long as self so this can not fail
+        let _ = rx.wait_for(|stopped| *stopped).await;
+    }
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        let all_done = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.grace_period(), all_done)
+            .await
+            .is_err()
+        {
+            eprintln!(
+                "Grace period of {:?} elapsed, aborting {} connections",
+                self.grace_period(),
+                connections.len()
+            );
+            connections.abort_all();
+            while connections.join_next().await.is_some() {}
+        }
+    }
+}
+impl Default for ServerHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A router with one or more bound listeners that are not serving yet.
+// All listeners share the routes, the state and the ServerHandle
+#[derive(Debug)]
+pub struct Server<T: 'static> {
+    routes: RouteTable<T>,
+    listeners: Vec<BoundListener>,
+    handle: ServerHandle,
+}
+struct BoundListener {
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+}
+impl std::fmt::Debug for BoundListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BoundListener")
+            .field("listener", &self.listener)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+impl<T> Server<T>
+where
+    T: Sync,
+    T: Send,
+    T: 'static,
+{
+    pub(crate) fn new(routes: RouteTable<T>) -> Self {
+        Server {
+            routes,
+            listeners: Vec::new(),
+            handle: ServerHandle::new(),
+        }
+    }
+    pub async fn bind(self, addr: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(self.with_listener(listener))
+    }
+    pub async fn bind_tls(self, addr: &str, path_to_cert: &str) -> std::io::Result<Self> {
+        let acceptor = crate::tls::load_acceptor(path_to_cert)?;
+        let listener = TcpListener::bind(addr).await?;
+        Ok(self.add_listener(listener.into(), Some(acceptor)))
+    }
+    #[cfg(unix)]
+    pub fn bind_unix(
+        self,
+        path: impl AsRef<std::path::Path>,
+        mode: Option<u32>,
+    ) -> std::io::Result<Self> {
+        let listener = crate::unix::bind_unix_listener(path.as_ref(), mode)?;
+        Ok(self.with_listener(listener))
+    }
+    pub fn with_listener(self, listener: impl Into<Listener>) -> Self {
+        self.add_listener(listener.into(), None)
+    }
+    // Adds the listening sockets taken with systemd::listeners_from_env before the runtime started.
+    // Fails if one of them is named https, those have to be added with with_activated_tls_listeners
+    // so they are not served without tls
+    #[cfg(unix)]
+    pub fn with_activated_listeners(
+        self,
+        activated: Vec<crate::systemd::ActivatedListener>,
+    ) -> std::io::Result<Self> {
+        self.add_activated(activated, None)
+    }
+    // Same as with_activated_listeners, the sockets named https are served over tls with the
+    // certificate at path_to_cert. serve_with_hot_restart hands over tls listeners with that name
+    #[cfg(unix)]
+    pub fn with_activated_tls_listeners(
+        self,
+        activated: Vec<crate::systemd::ActivatedListener>,
+        path_to_cert: &str,
+    ) -> std::io::Result<Self> {
+        let acceptor = crate::tls::load_acceptor(path_to_cert)?;
+        self.add_activated(activated, Some(acceptor))
+    }
+    #[cfg(unix)]
+    fn add_activated(
+        self,
+        activated: Vec<crate::systemd::ActivatedListener>,
+        acceptor: Option<TlsAcceptor>,
+    ) -> std::io::Result<Self> {
+        let mut server = self;
+        for activated in activated {
+            let tls = match (activated.name.as_deref(), &acceptor) {
+                (Some("https"), Some(acceptor)) => Some(acceptor.clone()),
+                (Some("https"), None) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "a socket named https was passed in, it needs a certificate",
+                    ))
+                }
+                _ => None,
+            };
+            server = server.add_listener(activated.into_listener()?, tls);
+        }
+        Ok(server)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+    // Switches user / group and optionally chroots once everything is bound and before the first
+    // connection is accepted. Tls listeners have to be bound with bind_tls before this since the
+    // certificates might not be readable afterwards
+    #[cfg(unix)]
+    pub fn drop_privileges(
+        self,
+        privileges: &crate::privileges::DropPrivileges,
+    ) -> std::io::Result<Self> {
+        privileges.apply()?;
+        Ok(self)
+    }
+    pub fn with_tls_listener(
+        self,
+        listener: impl Into<Listener>,
+        path_to_cert: &str,
+    ) -> std::io::Result<Self> {
+        let acceptor = crate::tls::load_acceptor(path_to_cert)?;
+        Ok(self.add_listener(listener.into(), Some(acceptor)))
+    }
+    fn add_listener(mut self, listener: Listener, tls: Option<TlsAcceptor>) -> Self {
+        if let Ok(addr) = listener.local_addr() {
+            self.handle.add_local_addr(addr);
+        }
+        self.listeners.push(BoundListener { listener, tls });
+        self
+    }
+    // Address of the first tcp listener
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self.local_addrs().first() {
+            Some(addr) => Ok(*addr),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no tcp listener is bound",
+            )),
+        }
+    }
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|bound| bound.listener.local_addr().ok())
+            .collect()
+    }
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+    // Replaces the router while serving, see RouteTable
+    pub fn route_table(&self) -> RouteTable<T> {
+        self.routes.clone()
+    }
+    pub fn with_handle(mut self, handle: ServerHandle) -> Self {
+        for addr in self.local_addrs() {
+            handle.add_local_addr(addr);
+        }
+        self.handle = handle;
+        self
+    }
+    // Serves all listeners until the handle is shut down.
+    // If one listener fails the others are shut down as well and the error is returned
+    pub async fn serve(self) -> std::io::Result<()> {
+        let mut running = JoinSet::new();
+        for bound in self.listeners {
+            let handle = self.handle.clone();
+            let routes = self.routes.clone();
+            running.spawn(routes.serve_on(bound.listener, bound.tls, handle));
+        }
+        let mut result = Ok(());
+        while let Some(res) = running.join_next().await {
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            if let Err(e) = res {
+                self.handle.shutdown();
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+    // Serves every listener that was bound without tls over tls as well
+    pub async fn serve_tls(mut self, path_to_cert: &str) -> std::io::Result<()> {
+        let acceptor = crate::tls::load_acceptor(path_to_cert)?;
+        for bound in self.listeners.iter_mut() {
+            if bound.tls.is_none() {
+                bound.tls = Some(acceptor.clone());
+            }
+        }
+        self.serve().await
+    }
+    pub async fn serve_with_shutdown(
+        self,
+        signal: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        let handle = self.handle.clone();
+        shutdown_on(handle, signal, self.serve()).await
+    }
+    // Zero downtime upgrades. On SIGUSR2 the binary is started again with the listening sockets
+    // handed over (see restart::spawn_with_listeners), this process then stops accepting and
+    // drains its connections while the new one already accepts. The new process has to pick up
+    // the sockets with systemd::listeners_from_env and bind_activated / bind_activated_or, or
+    // bind_activated_tls if some of them are tls listeners.
+    // Resolves after the hand over and the drain, or on SIGTERM / SIGINT like serve_with_shutdown
+    #[cfg(unix)]
+    pub async fn serve_with_hot_restart(self) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut upgrade_signal = signal(SignalKind::user_defined2())?;
+        let fds: Vec<(std::os::fd::RawFd, &'static str)> = self
+            .listeners
+            .iter()
+            .map(|bound| {
+                let name = match (&bound.listener, &bound.tls) {
+                    (_, Some(_)) => "https",
+                    (Listener::Tcp(_), None) => "http",
+                    (Listener::Unix(_), None) => "unix",
+                };
+                (bound.listener.as_raw_fd(), name)
+            })
+            .collect();
+        let upgrade = async move {
+            loop {
+                upgrade_signal.recv().await;
+                let command = match crate::restart::current_exe_command() {
+                    Ok(command) => command,
+                    Err(e) => {
+                        eprintln!("Cannot restart: {e}");
+                        continue;
+                    }
+                };
+                // The listeners are still open since serve only returns after this resolves
+                match crate::restart::spawn_with_listeners(command, &fds) {
+                    Ok(child) => {
+                        eprintln!("Started new process {}, draining connections", child.id());
+                        return;
+                    }
+                    Err(e) => eprintln!("Cannot restart: {e}"),
+                }
+            }
+        };
+        let stop = async {
+            tokio::select! {
+                _ = upgrade => (),
+                _ = shutdown_signal() => (),
+            }
+        };
+        let handle = self.handle.clone();
+        shutdown_on(handle, stop, self.serve()).await
+    }
+    pub async fn serve_tls_with_shutdown(
+        self,
+        path_to_cert: &str,
+        signal: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        let handle = self.handle.clone();
+        shutdown_on(handle, signal, self.serve_tls(path_to_cert)).await
+    }
+}
+// Drives the server and triggers the shutdown of its handle once signal resolves
+async fn shutdown_on(
+    handle: ServerHandle,
+    signal: impl Future<Output = ()>,
+    server: impl Future<Output = std::io::Result<()>>,
+) -> std::io::Result<()> {
+    tokio::pin!(server);
+    tokio::select! {
+        res = &mut server => return res,
+        _ = signal => handle.shutdown(),
+    }
+    server.await
+}
+
+// Resolves on SIGTERM or SIGINT (ctrl-c), meant to be passed to serve_with_shutdown
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Cannot listen for ctrl-c: {e}");
+            std::future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Cannot listen for SIGTERM: {e}");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
+
+// Accept loop shared by the plain and tls servers. Every connection is handled in its own task
+// by on_conn, once the handle is shut down the loop stops and the open connections are drained.
+// on_conn is expected to close connections that are idle on shutdown by waiting for the next
+// request together with ServerHandle::stopped, like handle_conn_node_based does. Otherwise they
+// are only aborted after the grace period
+pub async fn serve_listener<F, Fut>(
+    listener: Listener,
+    handle: ServerHandle,
+    on_conn: F,
+) -> std::io::Result<()>
+where
+    F: Fn(Stream) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut backoff = AcceptBackoff::new();
+    let mut connections = JoinSet::new();
+    loop {
+        let socket = tokio::select! {
+            conn = backoff.accept(&listener) => conn?,
+            _ = handle.stopped() => break,
+            // reap finished connections so the set does not grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
+        connections.spawn(on_conn.clone()(socket));
+    }
+    drop(listener);
+    handle.drain(connections).await;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+#![forbid(unsafe_code)]
+use std::any::Any;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+// Cheap handle to the application state. The state itself is stored once behind an Arc
+// so handing it to a handler is only a refcount bump instead of a deep clone
+pub struct State<T>(pub Arc<T>);
+
+impl<T> State<T> {
+    pub fn new(state: T) -> Self {
+        State(Arc::new(state))
+    }
+}
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        State(Arc::clone(&self.0))
+    }
+}
+impl<T> Deref for State<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+impl<T: fmt::Debug> fmt::Debug for State<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("State").field(&self.0).finish()
+    }
+}
+
+// Used to extract a part of the application state for handlers and nested routers.
+// A handler that only needs a DbPool can take State<DbPool> as long as DbPool: FromRef<AppState>.
+// The extracted value is created per request so it should be cheap to clone (e.g. an Arc inside)
+pub trait FromRef<T> {
+    fn from_ref(input: &T) -> Self;
+}
+impl<T: Clone> FromRef<T> for T {
+    fn from_ref(input: &T) -> Self {
+        input.clone()
+    }
+}
+impl<T: 'static> State<T> {
+    // If S is T the state is shared instead of cloned, e.g. for a nested router that uses the
+    // state of its parent
+    pub fn substate<S: FromRef<T> + 'static>(&self) -> State<S> {
+        match self.downcast() {
+            Some(state) => state,
+            None => State::new(S::from_ref(&self.0)),
+        }
+    }
+    // The same state as a State<S>, None if S is another type
+    pub fn downcast<S: 'static>(&self) -> Option<State<S>> {
+        (self as &dyn Any).downcast_ref::<State<S>>().cloned()
+    }
+}
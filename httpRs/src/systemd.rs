@@ -0,0 +1,76 @@
+#![forbid(unsafe_code)]
+use crate::server::Listener;
+use listenfd::ListenFd;
+use std::io::ErrorKind;
+
+// A listening socket that was passed in by systemd or another supervisor
+#[derive(Debug)]
+pub struct ActivatedListener {
+    // Name from LISTEN_FDNAMES, set with FileDescriptorName= in the .socket unit
+    pub name: Option<String>,
+    pub socket: ActivatedSocket,
+}
+// Kept as a std socket until the server is built, turning it into a tokio listener needs
+// a running runtime
+#[derive(Debug)]
+pub enum ActivatedSocket {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+impl ActivatedListener {
+    // Has to be called from within a tokio runtime
+    pub fn into_listener(self) -> std::io::Result<Listener> {
+        match self.socket {
+            ActivatedSocket::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(tokio::net::TcpListener::from_std(listener)?.into())
+            }
+            ActivatedSocket::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(tokio::net::UnixListener::from_std(listener)?.into())
+            }
+        }
+    }
+}
+
+// Takes the listening sockets passed with the LISTEN_FDS / LISTEN_PID convention.
+// Returns an empty Vec if the process was not socket activated.
+// listenfd removes LISTEN_FDS and LISTEN_PID from the environment so child processes do not
+// try to use the sockets as well. Changing the environment while another thread reads it
+// (getaddrinfo does) is a data race, so this has to be called in main before the tokio runtime
+// is started and fails when it is called from within one
+pub fn listeners_from_env() -> std::io::Result<Vec<ActivatedListener>> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(std::io::Error::other(
+            "socket activated listeners have to be taken before the tokio runtime is started",
+        ));
+    }
+    let names: Vec<String> = match std::env::var("LISTEN_FDNAMES") {
+        Ok(names) => names.split(':').map(|name| name.to_string()).collect(),
+        Err(_) => Vec::new(),
+    };
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::with_capacity(fds.len());
+    for idx in 0..fds.len() {
+        let socket = match take_socket(&mut fds, idx)? {
+            Some(socket) => socket,
+            None => continue,
+        };
+        listeners.push(ActivatedListener {
+            name: names.get(idx).filter(|name| !name.is_empty()).cloned(),
+            socket,
+        });
+    }
+    Ok(listeners)
+}
+
+fn take_socket(fds: &mut ListenFd, idx: usize) -> std::io::Result<Option<ActivatedSocket>> {
+    // take_tcp_listener validates the socket family and leaves the fd in place if it does not match
+    match fds.take_tcp_listener(idx) {
+        Ok(Some(listener)) => return Ok(Some(ActivatedSocket::Tcp(listener))),
+        Ok(None) => return Ok(None),
+        Err(e) if e.kind() == ErrorKind::InvalidInput => (),
+        Err(e) => return Err(e),
+    }
+    Ok(fds.take_unix_listener(idx)?.map(ActivatedSocket::Unix))
+}
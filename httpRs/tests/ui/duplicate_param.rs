@@ -0,0 +1,6 @@
+#[httpRs::get("/user/:id/post/:id")]
+async fn show_post(id: u32) -> String {
+    id.to_string()
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[httpRs::get("/user")]
+async fn show_user(id: u32) -> String {
+    id.to_string()
+}
+
+fn main() {}
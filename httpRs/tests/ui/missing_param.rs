@@ -0,0 +1,6 @@
+#[httpRs::get("/user/:id")]
+async fn show_user() -> &'static str {
+    "user"
+}
+
+fn main() {}